@@ -0,0 +1,57 @@
+//! Just enough AML to read the sleep type values of the `\_Sx` objects.
+//!
+//! Firmware declares them as `Name (_S5, Package () { a, b, ... })`,
+//! which compiles to NameOp, the name, PackageOp, the package length,
+//! the element count and the elements. Rather than interpret the whole
+//! table, `sleep_types` looks for that byte sequence, which finds every
+//! sleep state declared with a plain `Name`.
+
+use super::{u16_at, u32_at};
+
+const NAME_OP: u8 = 0x08;
+const ROOT_CHAR: u8 = b'\\';
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0A;
+const WORD_PREFIX: u8 = 0x0B;
+const DWORD_PREFIX: u8 = 0x0C;
+
+/// The values for the SLP_TYP fields of the PM1a and PM1b control
+/// registers which enter a sleep state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepTypes {
+    pub a: u8,
+    pub b: u8,
+}
+
+/// Finds the sleep type values of sleep state `state` (0 to 5) in `aml`.
+pub fn sleep_types(aml: &[u8], state: u8) -> Option<SleepTypes> {
+    let name = [b'_', b'S', b'0' + state, b'_'];
+    (0..=aml.len().saturating_sub(name.len()))
+        .filter(|&i| aml[i..].starts_with(&name))
+        .find_map(|i| {
+            let declared = aml[..i].ends_with(&[NAME_OP]) || aml[..i].ends_with(&[NAME_OP, ROOT_CHAR]);
+            let package = aml[i + name.len()..].strip_prefix(&[PACKAGE_OP])?;
+            if !declared {
+                return None;
+            }
+            // The top two bits of the first length byte count the bytes which follow it.
+            let elements = package.get(1 + (*package.first()? >> 6) as usize + 1..)?;
+            let (a, used) = integer(elements)?;
+            let b = elements.get(used..).and_then(integer).map_or(0, |(b, _)| b);
+            Some(SleepTypes { a: a as u8, b: b as u8 })
+        })
+}
+
+/// Decodes an integer constant, returning it and its length in bytes.
+fn integer(bytes: &[u8]) -> Option<(u64, usize)> {
+    match *bytes.first()? {
+        ZERO_OP => Some((0, 1)),
+        ONE_OP => Some((1, 1)),
+        BYTE_PREFIX => Some((*bytes.get(1)? as u64, 2)),
+        WORD_PREFIX => Some((u16_at(bytes, 1)? as u64, 3)),
+        DWORD_PREFIX => Some((u32_at(bytes, 1)? as u64, 5)),
+        _ => None,
+    }
+}
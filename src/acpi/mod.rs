@@ -0,0 +1,235 @@
+//! ACPI table discovery.
+//!
+//! The kernel boots through `bootloader` 0.9, which doesn't pass on the
+//! RSDP, so `rsdp` searches the places a BIOS puts it: the first KiB of
+//! the extended BIOS data area and the BIOS area from 0xE0000 to 0xFFFFF.
+//! The XSDT, or the RSDT on ACPI 1.0 machines, then lists every other
+//! table. Tables are read in place through the physical memory mapping,
+//! and only tables with a valid checksum are handed out by `find`.
+//!
+//! Nothing is cached or allocated: every lookup walks the root table
+//! again, which is cheap, and works as soon as `memory::init` has run.
+
+pub mod aml;
+mod tables;
+
+pub use aml::SleepTypes;
+pub use tables::{
+    AddressSpace, Fadt, GenericAddress, Hpet, Madt, MadtEntries, MadtEntry, Mcfg, McfgEntry,
+};
+
+use core::convert::TryInto;
+use x86_64::PhysAddr;
+use crate::memory;
+
+/// The size of the header every system description table starts with.
+const HEADER_SIZE: usize = 36;
+
+/// Longer tables are assumed to be garbage.
+const MAX_TABLE_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// `memory::init` has not run, so physical memory can't be read.
+    NotMapped,
+    /// No RSDP with a valid checksum was found.
+    NoRsdp,
+    /// The RSDT or XSDT failed its checksum.
+    InvalidRoot,
+}
+
+/// Returns `len` bytes of physical memory at `addr`.
+fn physical(addr: u64, len: usize) -> Result<&'static [u8], AcpiError> {
+    let offset = memory::physical_memory_offset().ok_or(AcpiError::NotMapped)?;
+    Ok(unsafe { core::slice::from_raw_parts((offset + addr).as_ptr(), len) })
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// Reads a fixed-size identifier, which is ASCII padded with spaces.
+fn identifier(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("?").trim_end_matches(&[' ', '\0'][..])
+}
+
+/// The root system description pointer.
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp {
+    addr: PhysAddr,
+    bytes: &'static [u8],
+}
+
+impl Rsdp {
+    /// Where the RSDP was found.
+    pub fn addr(&self) -> PhysAddr {
+        self.addr
+    }
+
+    /// 0 for ACPI 1.0, 2 for ACPI 2.0 and later.
+    pub fn revision(&self) -> u8 {
+        self.bytes[15]
+    }
+
+    pub fn oem_id(&self) -> &'static str {
+        identifier(&self.bytes[9..15])
+    }
+
+    pub fn rsdt_address(&self) -> PhysAddr {
+        PhysAddr::new(u32_at(self.bytes, 16).unwrap() as u64)
+    }
+
+    /// The address of the XSDT, which ACPI 2.0 and later provide.
+    pub fn xsdt_address(&self) -> Option<PhysAddr> {
+        if self.revision() < 2 || !checksum_ok(self.bytes) {
+            return None;
+        }
+        match u64_at(self.bytes, 24).unwrap() {
+            0 => None,
+            addr => Some(PhysAddr::new(addr)),
+        }
+    }
+}
+
+/// Finds the RSDP in the first KiB of the EBDA or in the BIOS area.
+pub fn rsdp() -> Result<Rsdp, AcpiError> {
+    let ebda = (u16_at(physical(0x40E, 2)?, 0).unwrap() as u64) << 4;
+    let areas = [(ebda, 1024), (0xE0000, 0x20000)];
+    for &(start, len) in areas.iter().filter(|&&(start, _)| start != 0) {
+        let area = physical(start, len)?;
+        for (i, candidate) in area.chunks_exact(16).enumerate() {
+            // The ACPI 1.0 checksum only covers the first 20 bytes.
+            if candidate.starts_with(b"RSD PTR ") && checksum_ok(&area[i * 16..][..20]) {
+                let addr = start + i as u64 * 16;
+                return Ok(Rsdp { addr: PhysAddr::new(addr), bytes: physical(addr, 36)? });
+            }
+        }
+    }
+    Err(AcpiError::NoRsdp)
+}
+
+/// A system description table, read in place.
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    addr: PhysAddr,
+    bytes: &'static [u8],
+}
+
+impl Table {
+    /// Reads the table at `addr`, without checking its checksum.
+    pub fn at(addr: PhysAddr) -> Result<Table, AcpiError> {
+        let header = physical(addr.as_u64(), HEADER_SIZE)?;
+        let len = u32_at(header, 4).unwrap() as usize;
+        let bytes = if (HEADER_SIZE..=MAX_TABLE_SIZE).contains(&len) {
+            physical(addr.as_u64(), len)?
+        } else {
+            header
+        };
+        Ok(Table { addr, bytes })
+    }
+
+    pub fn addr(&self) -> PhysAddr {
+        self.addr
+    }
+
+    pub fn signature(&self) -> &'static str {
+        identifier(&self.bytes[..4])
+    }
+
+    /// The length of the table, including the header.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.len() == HEADER_SIZE
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.bytes[8]
+    }
+
+    pub fn oem_id(&self) -> &'static str {
+        identifier(&self.bytes[10..16])
+    }
+
+    pub fn oem_table_id(&self) -> &'static str {
+        identifier(&self.bytes[16..24])
+    }
+
+    /// True if the table's bytes add up to zero, as they must. A table
+    /// whose length field is implausible never does.
+    pub fn checksum_valid(&self) -> bool {
+        u32_at(self.bytes, 4) == Some(self.bytes.len() as u32) && checksum_ok(self.bytes)
+    }
+
+    /// The whole table, including the header.
+    pub fn bytes(&self) -> &'static [u8] {
+        self.bytes
+    }
+}
+
+/// Returns every table the root table lists, valid or not.
+pub fn tables() -> Result<impl Iterator<Item = Table>, AcpiError> {
+    let rsdp = rsdp()?;
+    let (root, entry_size) = match rsdp.xsdt_address() {
+        Some(xsdt) => (Table::at(xsdt)?, 8),
+        None => (Table::at(rsdp.rsdt_address())?, 4),
+    };
+    if !root.checksum_valid() {
+        return Err(AcpiError::InvalidRoot);
+    }
+
+    Ok(root.bytes[HEADER_SIZE..].chunks_exact(entry_size).filter_map(move |entry| {
+        let addr = match entry_size {
+            8 => u64_at(entry, 0)?,
+            _ => u32_at(entry, 0)? as u64,
+        };
+        Table::at(PhysAddr::try_new(addr).ok()?).ok()
+    }))
+}
+
+/// Finds the first table with `signature` and a valid checksum.
+pub fn find(signature: &str) -> Option<Table> {
+    tables().ok()?.find(|table| table.signature() == signature && table.checksum_valid())
+}
+
+/// The MADT, which describes the interrupt controllers.
+pub fn madt() -> Option<Madt> {
+    Madt::new(find("APIC")?)
+}
+
+/// The FADT, which describes fixed hardware such as the power management registers.
+pub fn fadt() -> Option<Fadt> {
+    Fadt::new(find("FACP")?)
+}
+
+/// The HPET table, which describes the high precision event timer.
+pub fn hpet() -> Option<Hpet> {
+    Hpet::new(find("HPET")?)
+}
+
+/// The MCFG, which describes the PCI Express configuration space.
+pub fn mcfg() -> Option<Mcfg> {
+    Mcfg::new(find("MCFG")?)
+}
+
+/// The sleep type values of sleep state `state`, from the DSDT or an SSDT.
+pub fn sleep_types(state: u8) -> Option<SleepTypes> {
+    let ssdts = tables().ok()?
+        .filter(|table| table.signature() == "SSDT" && table.checksum_valid());
+    fadt()?.dsdt().into_iter().chain(ssdts)
+        .find_map(|table| aml::sleep_types(&table.bytes()[HEADER_SIZE..], state))
+}
@@ -0,0 +1,445 @@
+//! Typed views of the tables the kernel uses, and access to the
+//! registers they describe.
+//!
+//! Each view checks on creation that its table is long enough for the
+//! fields every revision has; fields added in later revisions are
+//! optional.
+
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+use crate::memory::{self, CacheMode, Mmio};
+use super::{u16_at, u32_at, u64_at, Table, HEADER_SIZE};
+
+/// The address space a `GenericAddress` refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfiguration,
+    Other(u8),
+}
+
+/// A register location in the ACPI generic address structure format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    /// 1 to 4 for byte to qword accesses, 0 if unspecified.
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// Parses the 12 bytes at `offset`.
+    fn parse(bytes: &[u8], offset: usize) -> Option<Self> {
+        let gas = bytes.get(offset..offset + 12)?;
+        Some(GenericAddress {
+            space: match gas[0] {
+                0 => AddressSpace::SystemMemory,
+                1 => AddressSpace::SystemIo,
+                2 => AddressSpace::PciConfiguration,
+                other => AddressSpace::Other(other),
+            },
+            bit_width: gas[1],
+            bit_offset: gas[2],
+            access_size: gas[3],
+            address: u64_at(gas, 4)?,
+        })
+    }
+
+    /// An I/O port block of `bytes` bytes, as the ACPI 1.0 fields describe them.
+    fn io_port(port: u32, bytes: u8) -> Self {
+        GenericAddress {
+            space: AddressSpace::SystemIo,
+            bit_width: bytes * 8,
+            bit_offset: 0,
+            access_size: 0,
+            address: port as u64,
+        }
+    }
+
+    /// The width of an access in bits: the access size if given,
+    /// otherwise the register's width rounded up.
+    fn access_width(&self) -> u8 {
+        match (self.access_size, self.bit_width) {
+            (1, _) | (0, 0..=8) => 8,
+            (2, _) | (0, 9..=16) => 16,
+            (3, _) | (0, 17..=32) => 32,
+            _ => 64,
+        }
+    }
+
+    /// Maps a memory register uncached for an access of `width` bits. The
+    /// physical memory mapping is cacheable, and need not cover device
+    /// memory at all. Returns None before `memory::install`, or if the
+    /// memory manager is locked, which it may be when the machine is shut
+    /// down from a panic.
+    unsafe fn map_register(&self, width: u8) -> Option<Mmio> {
+        let phys = PhysAddr::try_new(self.address).ok()?;
+        memory::try_map_mmio(phys, width as usize / 8, CacheMode::Uncached)?.ok()
+    }
+
+    /// Reads the register, or returns None if it is in an address space
+    /// the kernel can't access.
+    ///
+    /// # Safety
+    /// Reading hardware registers can have side effects.
+    pub unsafe fn read(&self) -> Option<u64> {
+        let width = self.access_width();
+        match self.space {
+            AddressSpace::SystemIo => {
+                let port = self.address as u16;
+                Some(match width {
+                    8 => Port::<u8>::new(port).read() as u64,
+                    16 => Port::<u16>::new(port).read() as u64,
+                    _ => Port::<u32>::new(port).read() as u64,
+                })
+            }
+            AddressSpace::SystemMemory => {
+                let register = self.map_register(width)?;
+                Some(match width {
+                    8 => register.read::<u8>(0) as u64,
+                    16 => register.read::<u16>(0) as u64,
+                    32 => register.read::<u32>(0) as u64,
+                    _ => register.read::<u64>(0),
+                })
+            }
+            AddressSpace::PciConfiguration => {
+                let (mut data, offset) = self.pci_select();
+                let shift = (offset & 3) * 8;
+                Some((data.read() >> shift) as u64 & (u64::MAX >> (64 - width.min(32) as u32)))
+            }
+            AddressSpace::Other(_) => None,
+        }
+    }
+
+    /// Writes the register, or returns false if it is in an address space
+    /// the kernel can't access.
+    ///
+    /// # Safety
+    /// Writing hardware registers can do anything, up to turning the machine off.
+    pub unsafe fn write(&self, value: u64) -> bool {
+        let width = self.access_width();
+        match self.space {
+            AddressSpace::SystemIo => {
+                let port = self.address as u16;
+                match width {
+                    8 => Port::<u8>::new(port).write(value as u8),
+                    16 => Port::<u16>::new(port).write(value as u16),
+                    _ => Port::<u32>::new(port).write(value as u32),
+                }
+            }
+            AddressSpace::SystemMemory => {
+                let register = match self.map_register(width) {
+                    Some(register) => register,
+                    None => return false,
+                };
+                match width {
+                    8 => register.write(0, value as u8),
+                    16 => register.write(0, value as u16),
+                    32 => register.write(0, value as u32),
+                    _ => register.write(0, value),
+                }
+            }
+            AddressSpace::PciConfiguration => {
+                // Only the data port bytes the register covers are written.
+                let (_, offset) = self.pci_select();
+                let port = PCI_CONFIG_DATA + (offset & 3) as u16;
+                match width {
+                    8 => Port::<u8>::new(port).write(value as u8),
+                    16 => Port::<u16>::new(port).write(value as u16),
+                    _ => Port::<u32>::new(port).write(value as u32),
+                }
+            }
+            AddressSpace::Other(_) => return false,
+        }
+        true
+    }
+
+    /// Selects the register's dword through the legacy PCI configuration
+    /// mechanism, returning the data port and the register's offset. The
+    /// address holds the device, function and offset on bus 0 of segment 0.
+    unsafe fn pci_select(&self) -> (Port<u32>, u8) {
+        let device = (self.address >> 32) as u32 & 0x1F;
+        let function = (self.address >> 16) as u32 & 0x7;
+        let offset = self.address as u8;
+        Port::<u32>::new(PCI_CONFIG_ADDRESS)
+            .write(1 << 31 | device << 11 | function << 8 | (offset & 0xFC) as u32);
+        (Port::new(PCI_CONFIG_DATA), offset)
+    }
+}
+
+/// The ports of the legacy PCI configuration mechanism.
+const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+const PCI_CONFIG_DATA: u16 = 0xCFC;
+
+/// The Multiple APIC Description Table, signature "APIC".
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    table: Table,
+}
+
+/// An entry of the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry {
+    LocalApic { processor_id: u8, apic_id: u8, flags: u32 },
+    IoApic { id: u8, address: PhysAddr, gsi_base: u32 },
+    /// Moves an ISA IRQ to another global system interrupt, with MPS
+    /// polarity and trigger mode flags.
+    InterruptSourceOverride { bus: u8, irq: u8, gsi: u32, flags: u16 },
+    LocalApicNmi { processor_id: u8, flags: u16, lint: u8 },
+    LocalApicAddressOverride { address: PhysAddr },
+    LocalX2Apic { x2apic_id: u32, flags: u32, processor_uid: u32 },
+    Other { kind: u8, len: u8 },
+}
+
+impl Madt {
+    pub fn new(table: Table) -> Option<Self> {
+        if table.len() < 44 {
+            return None;
+        }
+        Some(Madt { table })
+    }
+
+    pub fn table(&self) -> Table {
+        self.table
+    }
+
+    /// Where the local APICs' registers are, after any override.
+    pub fn local_apic_address(&self) -> PhysAddr {
+        self.entries()
+            .find_map(|entry| match entry {
+                MadtEntry::LocalApicAddressOverride { address } => Some(address),
+                _ => None,
+            })
+            .unwrap_or_else(|| PhysAddr::new(u32_at(self.table.bytes(), 36).unwrap() as u64))
+    }
+
+    /// True if the machine also has the dual 8259 PICs.
+    pub fn pcat_compatible(&self) -> bool {
+        u32_at(self.table.bytes(), 40).unwrap() & 1 != 0
+    }
+
+    pub fn entries(&self) -> MadtEntries {
+        MadtEntries { rest: &self.table.bytes()[44..] }
+    }
+}
+
+/// The entries of a MADT. Stops early at a malformed entry.
+#[derive(Debug, Clone)]
+pub struct MadtEntries {
+    rest: &'static [u8],
+}
+
+impl Iterator for MadtEntries {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        let (kind, len) = match *self.rest {
+            [kind, len, ..] if len >= 2 && len as usize <= self.rest.len() => (kind, len),
+            _ => return None,
+        };
+        let entry = &self.rest[..len as usize];
+        self.rest = &self.rest[len as usize..];
+
+        let parsed = match kind {
+            0 => Some(MadtEntry::LocalApic {
+                processor_id: *entry.get(2)?,
+                apic_id: *entry.get(3)?,
+                flags: u32_at(entry, 4)?,
+            }),
+            1 => Some(MadtEntry::IoApic {
+                id: *entry.get(2)?,
+                address: PhysAddr::new(u32_at(entry, 4)? as u64),
+                gsi_base: u32_at(entry, 8)?,
+            }),
+            2 => Some(MadtEntry::InterruptSourceOverride {
+                bus: *entry.get(2)?,
+                irq: *entry.get(3)?,
+                gsi: u32_at(entry, 4)?,
+                flags: u16_at(entry, 8)?,
+            }),
+            4 => Some(MadtEntry::LocalApicNmi {
+                processor_id: *entry.get(2)?,
+                flags: u16_at(entry, 3)?,
+                lint: *entry.get(5)?,
+            }),
+            5 => PhysAddr::try_new(u64_at(entry, 4)?).ok()
+                .map(|address| MadtEntry::LocalApicAddressOverride { address }),
+            9 => Some(MadtEntry::LocalX2Apic {
+                x2apic_id: u32_at(entry, 4)?,
+                flags: u32_at(entry, 8)?,
+                processor_uid: u32_at(entry, 12)?,
+            }),
+            _ => None,
+        };
+        Some(parsed.unwrap_or(MadtEntry::Other { kind, len }))
+    }
+}
+
+/// The Fixed ACPI Description Table, signature "FACP".
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    table: Table,
+}
+
+/// The FADT flag saying the reset register is supported.
+const RESET_REG_SUP: u32 = 1 << 10;
+
+impl Fadt {
+    /// Accepts ACPI 1.0 tables, which end after the flags.
+    pub fn new(table: Table) -> Option<Self> {
+        if table.len() < 116 {
+            return None;
+        }
+        Some(Fadt { table })
+    }
+
+    pub fn table(&self) -> Table {
+        self.table
+    }
+
+    fn bytes(&self) -> &'static [u8] {
+        self.table.bytes()
+    }
+
+    /// The Differentiated System Description Table, if it is valid.
+    pub fn dsdt(&self) -> Option<Table> {
+        let addr = match u64_at(self.bytes(), 140) {
+            Some(x_dsdt) if x_dsdt != 0 => x_dsdt,
+            _ => u32_at(self.bytes(), 40)? as u64,
+        };
+        let table = Table::at(PhysAddr::try_new(addr).ok()?).ok()?;
+        if table.signature() == "DSDT" && table.checksum_valid() { Some(table) } else { None }
+    }
+
+    /// The ISA IRQ the SCI is wired to.
+    pub fn sci_interrupt(&self) -> u16 {
+        u16_at(self.bytes(), 46).unwrap()
+    }
+
+    /// The port to write `acpi_enable` to to switch to ACPI mode, or 0
+    /// if the machine is always in ACPI mode.
+    pub fn smi_command_port(&self) -> u32 {
+        u32_at(self.bytes(), 48).unwrap()
+    }
+
+    pub fn acpi_enable(&self) -> u8 {
+        self.bytes()[52]
+    }
+
+    /// The PM1a control register block, which holds the sleep controls.
+    pub fn pm1a_control_block(&self) -> Option<GenericAddress> {
+        self.control_block(172, 64)
+    }
+
+    /// The optional PM1b control register block.
+    pub fn pm1b_control_block(&self) -> Option<GenericAddress> {
+        self.control_block(184, 68)
+    }
+
+    /// Prefers the extended field at `extended` over the ACPI 1.0 port at `legacy`.
+    fn control_block(&self, extended: usize, legacy: usize) -> Option<GenericAddress> {
+        match GenericAddress::parse(self.bytes(), extended) {
+            Some(gas) if gas.address != 0 => Some(gas),
+            _ => match u32_at(self.bytes(), legacy)? {
+                0 => None,
+                port => Some(GenericAddress::io_port(port, self.bytes()[89])),
+            },
+        }
+    }
+
+    pub fn flags(&self) -> u32 {
+        u32_at(self.bytes(), 112).unwrap()
+    }
+
+    /// The reset register and the value which resets the machine, if supported.
+    pub fn reset_register(&self) -> Option<(GenericAddress, u8)> {
+        if self.flags() & RESET_REG_SUP == 0 {
+            return None;
+        }
+        Some((GenericAddress::parse(self.bytes(), 116)?, *self.bytes().get(128)?))
+    }
+}
+
+/// The High Precision Event Timer table, signature "HPET".
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    table: Table,
+}
+
+impl Hpet {
+    pub fn new(table: Table) -> Option<Self> {
+        if table.len() < 56 {
+            return None;
+        }
+        Some(Hpet { table })
+    }
+
+    pub fn table(&self) -> Table {
+        self.table
+    }
+
+    /// Where the timer block's registers are.
+    pub fn base_address(&self) -> GenericAddress {
+        GenericAddress::parse(self.table.bytes(), 40).unwrap()
+    }
+
+    /// The number of comparators in the timer block.
+    pub fn comparators(&self) -> u8 {
+        ((u32_at(self.table.bytes(), 36).unwrap() >> 8) & 0x1F) as u8 + 1
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        (u32_at(self.table.bytes(), 36).unwrap() >> 16) as u16
+    }
+
+    pub fn hpet_number(&self) -> u8 {
+        self.table.bytes()[52]
+    }
+
+    /// The minimum period in ticks for periodic mode without lost interrupts.
+    pub fn minimum_tick(&self) -> u16 {
+        u16_at(self.table.bytes(), 53).unwrap()
+    }
+}
+
+/// The PCI Express memory mapped configuration table, signature "MCFG".
+#[derive(Debug, Clone, Copy)]
+pub struct Mcfg {
+    table: Table,
+}
+
+/// The ECAM region of one PCI segment group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    pub base_address: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl Mcfg {
+    pub fn new(table: Table) -> Option<Self> {
+        if table.len() < HEADER_SIZE + 8 {
+            return None;
+        }
+        Some(Mcfg { table })
+    }
+
+    pub fn table(&self) -> Table {
+        self.table
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = McfgEntry> {
+        self.table.bytes()[HEADER_SIZE + 8..].chunks_exact(16).filter_map(|entry| {
+            Some(McfgEntry {
+                base_address: PhysAddr::try_new(u64_at(entry, 0)?).ok()?,
+                segment: u16_at(entry, 8)?,
+                start_bus: entry[10],
+                end_bus: entry[11],
+            })
+        })
+    }
+}
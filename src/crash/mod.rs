@@ -0,0 +1,269 @@
+//! Crash reports for panics and fatal exceptions.
+//!
+//! A report shows the general purpose registers other than RDI, RFLAGS,
+//! the segment selectors and the control registers, followed by a
+//! backtrace found by following the saved frame pointers, which the
+//! target specification keeps in every function. Addresses in the backtrace are symbolised
+//! against the kernel's own symbol table, see `symbols`. Everything is
+//! printed to both VGA and serial, and nothing is allocated.
+//!
+//! A fatal exception hands the registers of the interrupted code to
+//! `set_context` before it panics, so that the report describes where
+//! the exception happened rather than the panic handler. Its handler
+//! only knows those in the interrupt stack frame and RBP, so the report
+//! shows just those.
+
+pub mod symbols;
+
+use core::fmt;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::{println, printsln};
+
+/// The most frames a report shows. More would scroll the
+/// panic message off the VGA screen.
+const MAX_FRAMES: usize = 16;
+
+/// Prints a line to both VGA and serial.
+macro_rules! report_line {
+    ($($arg:tt)*) => {{
+        println!($($arg)*);
+        printsln!($($arg)*);
+    }};
+}
+
+/// A snapshot of the CPU's registers. RDI is missing: `capture` needs it
+/// to address the buffer it stores the others in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cs: u64,
+    pub ss: u64,
+    /// Whether the general purpose registers other than RBP and RSP were captured.
+    pub general_purpose: bool,
+}
+
+/// The number of registers in `Registers`.
+const REGISTERS: usize = 19;
+
+impl Registers {
+    /// Captures the registers at the point of the call.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut captured = [0u64; REGISTERS];
+        unsafe {
+            core::arch::asm!(
+                "mov [rdi], rax",
+                "mov [rdi + 8], rbx",
+                "mov [rdi + 16], rcx",
+                "mov [rdi + 24], rdx",
+                "mov [rdi + 32], rsi",
+                "mov [rdi + 40], rbp",
+                "mov [rdi + 48], rsp",
+                "mov [rdi + 56], r8",
+                "mov [rdi + 64], r9",
+                "mov [rdi + 72], r10",
+                "mov [rdi + 80], r11",
+                "mov [rdi + 88], r12",
+                "mov [rdi + 96], r13",
+                "mov [rdi + 104], r14",
+                "mov [rdi + 112], r15",
+                "lea rax, [rip]",
+                "mov [rdi + 120], rax",
+                "pushfq",
+                "pop rax",
+                "mov [rdi + 128], rax",
+                "xor eax, eax",
+                "mov ax, cs",
+                "mov [rdi + 136], rax",
+                "mov ax, ss",
+                "mov [rdi + 144], rax",
+                in("rdi") captured.as_mut_ptr(),
+                out("rax") _,
+            );
+        }
+        let [rax, rbx, rcx, rdx, rsi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15, rip, rflags, cs, ss] =
+            captured;
+        Registers {
+            rax, rbx, rcx, rdx, rsi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15,
+            rip, rflags, cs, ss,
+            general_purpose: true,
+        }
+    }
+
+    /// The registers of the code interrupted by an exception, as far as
+    /// its handler can tell: those in the stack frame, and RBP, which the
+    /// handler's prologue saved. Must be called in the handler itself.
+    /// The other general purpose registers have been reused by then, so
+    /// they are left out.
+    #[inline(always)]
+    pub fn interrupted(stack_frame: &InterruptStackFrame) -> Self {
+        let rbp: u64;
+        unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        Registers {
+            rbp: unsafe { (rbp as *const u64).read() },
+            rip: stack_frame.instruction_pointer.as_u64(),
+            rsp: stack_frame.stack_pointer.as_u64(),
+            rflags: stack_frame.cpu_flags,
+            cs: stack_frame.code_segment,
+            ss: stack_frame.stack_segment,
+            ..Registers::default()
+        }
+    }
+}
+
+/// The registers a fatal exception recorded for the coming panic report.
+static CONTEXT: Mutex<Option<Registers>> = Mutex::new(None);
+
+/// Set while a panic is being reported, to catch panics in the report.
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+/// Records the registers of a fatal exception, which the report of the
+/// panic that follows shows instead of its own.
+pub fn set_context(registers: Registers) {
+    if let Some(mut context) = CONTEXT.try_lock() {
+        *context = Some(registers);
+    }
+}
+
+/// Prints the panic message followed by a crash report.
+pub fn report(info: &PanicInfo) {
+    let registers = Registers::capture();
+
+    if REPORTING.swap(true, Ordering::SeqCst) {
+        report_line!("panic while reporting a panic: {}", info);
+        return;
+    }
+    report_line!("KERNEL PANIC: {}", info);
+    let context = CONTEXT.try_lock().and_then(|mut context| context.take());
+    dump(&context.unwrap_or(registers));
+    REPORTING.store(false, Ordering::SeqCst);
+}
+
+/// Prints the registers and a backtrace starting at `registers.rip`.
+pub fn dump(registers: &Registers) {
+    use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+    let r = registers;
+    if r.general_purpose {
+        report_line!("RAX={:#018x} RBX={:#018x} RCX={:#018x}", r.rax, r.rbx, r.rcx);
+        report_line!("RDX={:#018x} RSI={:#018x} RBP={:#018x}", r.rdx, r.rsi, r.rbp);
+        report_line!("RSP={:#018x} R8 ={:#018x} R9 ={:#018x}", r.rsp, r.r8, r.r9);
+        report_line!("R10={:#018x} R11={:#018x} R12={:#018x}", r.r10, r.r11, r.r12);
+        report_line!("R13={:#018x} R14={:#018x} R15={:#018x}", r.r13, r.r14, r.r15);
+        report_line!("RIP={:#018x} RFLAGS={:#x}", r.rip, r.rflags);
+    } else {
+        report_line!("Interrupt stack frame (other general purpose registers unknown):");
+        report_line!("RBP={:#018x} RSP={:#018x} RIP={:#018x}", r.rbp, r.rsp, r.rip);
+        report_line!("RFLAGS={:#x}", r.rflags);
+    }
+
+    let (level_4_frame, cr3_flags) = Cr3::read();
+    let cr3 = level_4_frame.start_address().as_u64() | cr3_flags.bits();
+    report_line!("CR0={:#018x} CR2={:#018x} CR3={:#018x}", Cr0::read_raw(), Cr2::read().as_u64(), cr3);
+    report_line!("CR4={:#018x} CS={:#06x} SS={:#06x}", Cr4::read_raw(), r.cs, r.ss);
+
+    report_line!("Backtrace:");
+    for (i, address) in Backtrace::new(r.rip, r.rbp).take(MAX_FRAMES).enumerate() {
+        let location = if i == 0 { Location::at(address) } else { Location::returning_to(address) };
+        report_line!("  {:#018x} {}", address, location);
+    }
+}
+
+/// The return addresses on a stack, found by following the chain of
+/// saved frame pointers. The walk stops at the first frame pointer which
+/// is not mapped or does not lie above the one before it.
+pub struct Backtrace {
+    rip: Option<u64>,
+    rbp: u64,
+}
+
+impl Backtrace {
+    /// Starts a backtrace at the instruction `rip`, whose function's
+    /// frame pointer is `rbp`. `rip` is the first address returned.
+    pub fn new(rip: u64, rbp: u64) -> Self {
+        Backtrace { rip: Some(rip), rbp }
+    }
+}
+
+impl Iterator for Backtrace {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if let Some(rip) = self.rip.take() {
+            return Some(rip);
+        }
+        if self.rbp == 0 || self.rbp % 8 != 0 || !readable(self.rbp) || !readable(self.rbp + 8) {
+            return None;
+        }
+
+        let frame = self.rbp as *const u64;
+        let (saved_rbp, return_address) = unsafe { (frame.read(), frame.add(1).read()) };
+        // Callers' frames lie above ours on the same stack.
+        self.rbp = if saved_rbp > self.rbp { saved_rbp } else { 0 };
+        match return_address {
+            0 => None,
+            address => Some(address),
+        }
+    }
+}
+
+/// Returns true if `addr` can be read without faulting. Without
+/// the physical memory mapping that can't be told, so it's false.
+fn readable(addr: u64) -> bool {
+    let (addr, offset) = match (VirtAddr::try_new(addr), crate::memory::physical_memory_offset()) {
+        (Ok(addr), Some(offset)) => (addr, offset),
+        _ => return false,
+    };
+    unsafe { crate::memory::translate_addr(addr, offset) }.is_some()
+}
+
+/// Displays the function containing a code address and the offset into it.
+#[derive(Debug, Clone, Copy)]
+pub struct Location {
+    address: u64,
+    return_address: bool,
+}
+
+impl Location {
+    /// The location of the instruction at `address`.
+    pub fn at(address: u64) -> Self {
+        Location { address, return_address: false }
+    }
+
+    /// The location of the call returning to `address`. The return
+    /// address itself may already lie in the next function when the
+    /// call was the last instruction of its own.
+    pub fn returning_to(address: u64) -> Self {
+        Location { address, return_address: true }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lookup = if self.return_address { self.address.wrapping_sub(1) } else { self.address };
+        match symbols::lookup(lookup) {
+            Some(symbol) => write!(f, "{}+{:#x}", symbol.demangled(), self.address - symbol.start),
+            None => f.write_str("??"),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Symbolisation against the kernel's own symbol table.
+//!
+//! The linker writes the symbol table into the kernel's ELF file at build
+//! time. The bootloader loads the whole file into memory, keeps it
+//! reserved as kernel memory, and maps the first segment, which holds the
+//! ELF header, straight from the loaded file. So the physical address of
+//! `__ehdr_start` is where the file starts, and its `.symtab` and
+//! `.strtab` sections can be read through the physical memory mapping.
+//!
+//! Lookups only read memory and never allocate, so they work from a
+//! panic even with a broken heap. They need `memory::init`.
+
+use core::fmt;
+use core::convert::TryInto;
+use crate::memory::{self, kernel_image};
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+const SYMBOL_SIZE: usize = 24;
+
+/// Files larger than this are assumed to be garbage rather than the kernel.
+const MAX_FILE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// A function in the kernel's symbol table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    /// The name as the linker saw it, which for Rust is mangled.
+    pub name: &'static str,
+    pub start: u64,
+    pub size: u64,
+}
+
+impl Symbol {
+    pub fn demangled(&self) -> Demangled<'static> {
+        Demangled(self.name)
+    }
+}
+
+/// Returns the function containing `addr`, or None if there is none or
+/// the symbol table can't be found.
+pub fn lookup(addr: u64) -> Option<Symbol> {
+    let (symtab, strtab) = tables()?;
+    symtab.chunks_exact(SYMBOL_SIZE).find_map(|entry| {
+        let info = entry[4];
+        let start = u64_at(entry, 8)?;
+        let size = u64_at(entry, 16)?;
+        if info & 0xF != STT_FUNC || addr < start || addr - start >= size.max(1) {
+            return None;
+        }
+        let name = strtab.get(u32_at(entry, 0)? as usize..)?;
+        let len = name.iter().position(|&b| b == 0)?;
+        let name = core::str::from_utf8(&name[..len]).ok()?;
+        Some(Symbol { name, start, size })
+    })
+}
+
+/// Finds the symbol table and its string table in the loaded kernel file.
+fn tables() -> Option<(&'static [u8], &'static [u8])> {
+    let file = kernel_file()?;
+    let shoff = u64_at(file, 0x28)? as usize;
+    let shentsize = u16_at(file, 0x3A)? as usize;
+    let shnum = u16_at(file, 0x3C)? as usize;
+    let section = |index: usize| file.get(shoff + index * shentsize..)?.get(..shentsize);
+    let contents = |header: &[u8]| {
+        let offset = u64_at(header, 0x18)? as usize;
+        let size = u64_at(header, 0x20)? as usize;
+        file.get(offset..offset.checked_add(size)?)
+    };
+
+    let symtab = (0..shnum).filter_map(section).find(|header| u32_at(header, 4) == Some(SHT_SYMTAB))?;
+    let strtab = section(u32_at(symtab, 0x28)? as usize)?;
+    Some((contents(symtab)?, contents(strtab)?))
+}
+
+/// The kernel's ELF file as loaded by the bootloader, up to the end of
+/// the section headers.
+fn kernel_file() -> Option<&'static [u8]> {
+    let offset = memory::physical_memory_offset()?;
+    let start = unsafe { memory::translate_addr(kernel_image::elf_header(), offset) }?.addr;
+    let base: *const u8 = (offset + start.as_u64()).as_ptr();
+
+    let header = unsafe { core::slice::from_raw_parts(base, 64) };
+    if &header[..4] != b"\x7fELF" || header[4] != 2 {
+        return None;
+    }
+    let shoff = u64_at(header, 0x28)?;
+    let end = shoff + u16_at(header, 0x3A)? as u64 * u16_at(header, 0x3C)? as u64;
+    if end > MAX_FILE_SIZE {
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts(base, end as usize) })
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// Displays a symbol name demangled if it follows Rust's legacy mangling
+/// scheme, leaving out the hash, and as it is otherwise.
+#[derive(Debug, Clone, Copy)]
+pub struct Demangled<'a>(pub &'a str);
+
+impl fmt::Display for Demangled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = match legacy_path(self.0) {
+            Some(path) => path,
+            None => return f.write_str(self.0),
+        };
+
+        let mut rest = path;
+        let mut first = true;
+        while let Some((segment, remaining)) = next_segment(rest) {
+            rest = remaining;
+            if rest.is_empty() && is_hash(segment) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_segment(segment, f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the length-prefixed segments of a legacy mangled name,
+/// if that's what `name` is.
+fn legacy_path(name: &str) -> Option<&str> {
+    let path = name.strip_prefix("_ZN").or_else(|| name.strip_prefix("__ZN"))?.strip_suffix('E')?;
+    // Only accept names which consist of segments all the way through.
+    let mut rest = path;
+    while !rest.is_empty() {
+        rest = next_segment(rest)?.1;
+    }
+    if path.is_empty() { None } else { Some(path) }
+}
+
+/// Splits the first length-prefixed segment off `path`.
+fn next_segment(path: &str) -> Option<(&str, &str)> {
+    let digits = path.bytes().take_while(u8::is_ascii_digit).count();
+    let len: usize = path[..digits].parse().ok()?;
+    let rest = &path[digits..];
+    if len == 0 || rest.len() < len || !rest.is_char_boundary(len) {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+/// True for the `h` and 16 hex digits ending every legacy name.
+fn is_hash(segment: &str) -> bool {
+    segment.len() == 17
+        && segment.starts_with('h')
+        && segment[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Writes a segment with its escapes replaced.
+fn write_segment(segment: &str, f: &mut fmt::Formatter) -> fmt::Result {
+    // A leading underscore protects a segment which starts with an escape.
+    let mut rest = match segment.strip_prefix("_$") {
+        Some(_) => &segment[1..],
+        None => segment,
+    };
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = after;
+        } else if let Some(escape) = rest.strip_prefix('$').and_then(|after| after.split_once('$')) {
+            let (code, after) = escape;
+            match unescape(code) {
+                Some(c) => write!(f, "{}", c)?,
+                None => write!(f, "${}$", code)?,
+            }
+            rest = after;
+        } else {
+            let len = rest.char_indices().skip(1)
+                .find(|&(_, c)| c == '$' || c == '.')
+                .map_or(rest.len(), |(i, _)| i);
+            f.write_str(&rest[..len])?;
+            rest = &rest[len..];
+        }
+    }
+    Ok(())
+}
+
+fn unescape(code: &str) -> Option<char> {
+    Some(match code {
+        "SP" => '@',
+        "BP" => '*',
+        "RF" => '&',
+        "LT" => '<',
+        "GT" => '>',
+        "LP" => '(',
+        "RP" => ')',
+        "C" => ',',
+        _ => core::char::from_u32(u32::from_str_radix(code.strip_prefix('u')?, 16).ok()?)?,
+    })
+}
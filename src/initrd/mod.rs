@@ -4,9 +4,49 @@
 //! 
 use lazy_static::lazy_static;
 use alloc::{borrow::ToOwned, string::String, collections::BTreeMap, string::ToString};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+    PhysFrame, Size4KiB,
+};
+use crate::memory::{BuddyFrameAllocator, BORROWED};
+
+/// Wraps the archive so that it starts on a page boundary. File contents
+/// which then also start on one can be mapped without copying.
+#[repr(C, align(4096))]
+struct PageAligned<T: ?Sized>(T);
+
+static ARCHIVE: &PageAligned<[u8]> = &PageAligned(*include_bytes!("initrd.tar"));
 
 lazy_static! {
-    pub static ref INITRD: USTAR = USTAR::new(include_bytes!("initrd.tar"));
+    pub static ref INITRD: USTAR = USTAR::new(&ARCHIVE.0);
+}
+
+/// A file mapped into memory by `USTAR::map`.
+#[derive(Debug, Clone, Copy)]
+pub struct MappedFile {
+    /// Where the file's contents start.
+    pub start: VirtAddr,
+    /// The length of the file in bytes.
+    pub len: usize,
+    /// The number of pages mapped, including the zero-filled tail.
+    pub pages: u64,
+    /// How many of those pages share the archive's own frames.
+    pub shared_pages: u64,
+}
+
+#[derive(Debug)]
+pub enum MapFileError {
+    /// No file of that name exists.
+    NotFound,
+    /// Mapping the file failed. Pages mapped before the failure stay mapped.
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for MapFileError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        MapFileError::Map(error)
+    }
 }
 
 #[derive(Debug)]
@@ -65,6 +105,71 @@ impl USTAR {
         }
     }
 
+    /// Maps the file `name` read-only at `start`, with `flags` added to
+    /// every page (`WRITABLE` is ignored). Whole pages of file data that
+    /// are page aligned in the archive are mapped straight from the frames
+    /// holding it; the rest, including the partial last page, are copied
+    /// into fresh frames whose remainder is zero-filled.
+    pub fn map(
+        &self,
+        name: &str,
+        start: Page,
+        flags: PageTableFlags,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frames: &mut BuddyFrameAllocator,
+    ) -> Result<MappedFile, MapFileError> {
+        let header = self.headers.get(name).ok_or(MapFileError::NotFound)?;
+        let data = &self.blob[header.offset + 512..][..header.size as usize];
+        let offset = crate::memory::physical_memory_offset().expect("initrd mapped before memory::init");
+        let flags = (flags | PageTableFlags::PRESENT) - PageTableFlags::WRITABLE;
+        let table_flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | (flags & PageTableFlags::USER_ACCESSIBLE);
+        let aligned = VirtAddr::from_ptr(data.as_ptr()).is_aligned(4096u64);
+
+        let mut shared_pages = 0;
+        let chunks = data.chunks(4096);
+        let pages = chunks.len() as u64;
+        for (page, chunk) in Page::range(start, start + pages).zip(chunks) {
+            let borrowed = if aligned && chunk.len() == 4096 {
+                let virt = VirtAddr::from_ptr(chunk.as_ptr());
+                unsafe { crate::memory::translate_addr(virt, offset) }
+                    .map(|t| PhysFrame::<Size4KiB>::containing_address(t.addr))
+            } else {
+                None
+            };
+
+            let flush = match borrowed {
+                Some(frame) => {
+                    shared_pages += 1;
+                    unsafe { mapper.map_to_with_table_flags(page, frame, flags | BORROWED, table_flags, frames)? }
+                }
+                None => {
+                    let frame: PhysFrame = frames.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+                    unsafe {
+                        let dst: *mut u8 = (offset + frame.start_address().as_u64()).as_mut_ptr();
+                        core::ptr::copy_nonoverlapping(chunk.as_ptr(), dst, chunk.len());
+                    }
+                    match unsafe { mapper.map_to_with_table_flags(page, frame, flags, table_flags, frames) } {
+                        Ok(flush) => flush,
+                        Err(error) => {
+                            unsafe { frames.deallocate_frame(frame) };
+                            return Err(error.into());
+                        }
+                    }
+                }
+            };
+            flush.flush();
+        }
+
+        Ok(MappedFile {
+            start: start.start_address(),
+            len: data.len(),
+            pages,
+            shared_pages,
+        })
+    }
+
     pub fn entries(&self) -> alloc::vec::Vec<USTARHeader> {
         let mut v: alloc::vec::Vec<USTARHeader> = alloc::vec::Vec::new();
         for val in self.headers.values() {
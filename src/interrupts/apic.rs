@@ -0,0 +1,280 @@
+//! Support for the local APIC and the I/O APIC, which take over from the
+//! 8259 PICs on any machine that has them.
+//!
+//! `init` finds the interrupt controllers in the ACPI MADT, masks the
+//! PICs, enables the bootstrap processor's local APIC (in x2APIC mode
+//! when the CPU supports it) and routes the timer and keyboard IRQs
+//! through the I/O APIC to the vectors the PICs used, so the handlers
+//! stay the same. Machines without an APIC or ACPI tables, such as QEMU's
+//! `isapc`, keep using the PICs: `init` fails before touching anything.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use conquer_once::spin::OnceCell;
+use x86_64::registers::model_specific::Msr;
+use crate::acpi::{self, Madt, MadtEntry};
+use crate::memory::{self, vmm::VmmError, CacheMode, Mmio};
+use super::{InterruptIndex, PICS};
+
+/// The IA32_APIC_BASE model specific register.
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+
+/// In x2APIC mode the local APIC's registers are MSRs from here on,
+/// one for every 16 bytes of the xAPIC register page.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Local APIC registers, as offsets into the xAPIC register page.
+const LAPIC_ID: u32 = 0x20;
+const LAPIC_TPR: u32 = 0x80;
+const LAPIC_EOI: u32 = 0xB0;
+const LAPIC_SVR: u32 = 0xF0;
+/// The software enable bit of the spurious interrupt vector register.
+const SVR_ENABLE: u32 = 1 << 8;
+
+/// The vector the local APIC delivers spurious interrupts to.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// I/O APIC registers, selected through IOREGSEL.
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// The ISA IRQs routed through the I/O APIC, and their vectors.
+const ROUTES: [(u8, InterruptIndex); 2] = [
+    (0, InterruptIndex::Timer),
+    (1, InterruptIndex::Keyboard),
+];
+
+#[derive(Debug)]
+pub enum ApicError {
+    /// CPUID reports no local APIC.
+    NoApic,
+    /// No valid MADT was found.
+    NoMadt,
+    /// The MADT lists no I/O APIC for this ISA IRQ.
+    NoIoApic(u8),
+    /// Mapping the controllers' registers failed.
+    Map(VmmError),
+    /// `init` already ran.
+    AlreadyInitialized,
+}
+
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+static IO_APICS: OnceCell<Vec<IoApic>> = OnceCell::uninit();
+
+/// Set once the APICs have taken over from the PICs.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns true if interrupts are delivered through the APICs.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Switches interrupt delivery from the PICs to the APICs.
+/// Requires `memory::install`.
+pub fn init() -> Result<(), ApicError> {
+    if LOCAL_APIC.is_initialized() {
+        return Err(ApicError::AlreadyInitialized);
+    }
+    // CPUID.01h:EDX[9] reports a local APIC, ECX[21] x2APIC support.
+    let features = core::arch::x86_64::__cpuid(1);
+    if features.edx & (1 << 9) == 0 {
+        return Err(ApicError::NoApic);
+    }
+    let x2apic = features.ecx & (1 << 21) != 0;
+    let madt = acpi::madt().ok_or(ApicError::NoMadt)?;
+
+    // Map everything before changing anything, so failures leave the PICs in charge.
+    let mut io_apics = Vec::new();
+    for entry in madt.entries() {
+        let (address, gsi_base) = match entry {
+            MadtEntry::IoApic { address, gsi_base, .. } => (address, gsi_base),
+            _ => continue,
+        };
+        let mmio = unsafe { memory::map_mmio(address, 0x20, CacheMode::Uncached) }
+            .map_err(ApicError::Map)?;
+        io_apics.push(IoApic::new(mmio, gsi_base));
+    }
+    for &(irq, _) in ROUTES.iter() {
+        let (gsi, _) = isa_irq(&madt, irq);
+        if !io_apics.iter().any(|io_apic| io_apic.handles(gsi)) {
+            return Err(ApicError::NoIoApic(irq));
+        }
+    }
+    let local_apic = if x2apic {
+        LocalApic::X2Apic
+    } else {
+        let mmio = unsafe { memory::map_mmio(madt.local_apic_address(), 0x400, CacheMode::Uncached) }
+            .map_err(ApicError::Map)?;
+        LocalApic::XApic(mmio)
+    };
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        unsafe {
+            PICS.lock().disable();
+
+            let mut base = Msr::new(IA32_APIC_BASE);
+            let value = base.read() | APIC_BASE_ENABLE;
+            base.write(value);
+            if x2apic {
+                base.write(value | APIC_BASE_X2APIC);
+            }
+        }
+        local_apic.write(LAPIC_TPR, 0);
+        local_apic.write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+        let destination = local_apic.id();
+        for io_apic in io_apics.iter() {
+            io_apic.mask_all();
+        }
+        for &(irq, index) in ROUTES.iter() {
+            let (gsi, flags) = isa_irq(&madt, irq);
+            let io_apic = io_apics.iter().find(|io_apic| io_apic.handles(gsi)).unwrap();
+            io_apic.route(gsi, index.as_u8(), flags, destination);
+        }
+
+        LOCAL_APIC.try_init_once(|| local_apic).unwrap();
+        IO_APICS.try_init_once(|| io_apics).unwrap();
+        ENABLED.store(true, Ordering::Relaxed);
+    });
+    Ok(())
+}
+
+/// Signals the end of an interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    if let Ok(local_apic) = LOCAL_APIC.try_get() {
+        local_apic.write(LAPIC_EOI, 0);
+    }
+}
+
+/// The ID of the bootstrap processor's local APIC, once enabled.
+pub fn local_apic_id() -> Option<u32> {
+    LOCAL_APIC.try_get().ok().map(LocalApic::id)
+}
+
+/// Returns true if the local APIC runs in x2APIC mode.
+pub fn x2apic() -> bool {
+    matches!(LOCAL_APIC.try_get(), Ok(LocalApic::X2Apic))
+}
+
+/// The bootstrap processor's local APIC.
+enum LocalApic {
+    XApic(Mmio),
+    X2Apic,
+}
+
+impl LocalApic {
+    fn read(&self, register: u32) -> u32 {
+        match self {
+            LocalApic::XApic(mmio) => mmio.read(register as usize),
+            LocalApic::X2Apic => unsafe { Msr::new(X2APIC_MSR_BASE + (register >> 4)).read() as u32 },
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        match self {
+            LocalApic::XApic(mmio) => mmio.write(register as usize, value),
+            LocalApic::X2Apic => unsafe { Msr::new(X2APIC_MSR_BASE + (register >> 4)).write(value as u64) },
+        }
+    }
+
+    fn id(&self) -> u32 {
+        match self {
+            LocalApic::XApic(_) => self.read(LAPIC_ID) >> 24,
+            LocalApic::X2Apic => self.read(LAPIC_ID),
+        }
+    }
+}
+
+/// An I/O APIC, whose redirection entries map a range of global
+/// system interrupts (GSIs) to vectors.
+struct IoApic {
+    mmio: Mmio,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn new(mmio: Mmio, gsi_base: u32) -> Self {
+        let mut io_apic = IoApic { mmio, gsi_base, entries: 0 };
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+        io_apic
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        self.mmio.write(0x00, register);
+        self.mmio.read(0x10)
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        self.mmio.write(0x00, register);
+        self.mmio.write(0x10, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.entries
+    }
+
+    fn set_entry(&self, index: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + index * 2;
+        // Mask while the entry is half written.
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    fn mask_all(&self) {
+        for index in 0..self.entries {
+            self.set_entry(index, REDIRECTION_MASKED);
+        }
+    }
+
+    /// Delivers `gsi` as `vector` to the local APIC with ID `destination`.
+    fn route(&self, gsi: u32, vector: u8, flags: IrqFlags, destination: u32) {
+        let mut entry = vector as u64 | (destination as u64 & 0xFF) << 56;
+        if flags.active_low {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if flags.level_triggered {
+            entry |= REDIRECTION_LEVEL;
+        }
+        self.set_entry(gsi - self.gsi_base, entry);
+    }
+}
+
+/// How an interrupt line signals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IrqFlags {
+    active_low: bool,
+    level_triggered: bool,
+}
+
+impl IrqFlags {
+    /// Decodes the MPS flags of an interrupt source override, where the
+    /// bus's conforming defaults are those of ISA.
+    fn from_mps(flags: u16) -> Self {
+        IrqFlags {
+            active_low: flags & 0b11 == 0b11,
+            level_triggered: (flags >> 2) & 0b11 == 0b11,
+        }
+    }
+}
+
+/// ISA IRQs are active high and edge triggered unless overridden.
+const ISA_FLAGS: IrqFlags = IrqFlags { active_low: false, level_triggered: false };
+
+/// The GSI and signalling of an ISA IRQ.
+fn isa_irq(madt: &Madt, irq: u8) -> (u32, IrqFlags) {
+    madt.entries()
+        .find_map(|entry| match entry {
+            MadtEntry::InterruptSourceOverride { irq: source, gsi, flags, .. } if source == irq => {
+                Some((gsi, IrqFlags::from_mps(flags)))
+            }
+            _ => None,
+        })
+        .unwrap_or((irq as u32, ISA_FLAGS))
+}
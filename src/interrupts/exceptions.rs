@@ -0,0 +1,171 @@
+//! Handlers for the CPU exceptions without special treatment elsewhere.
+//!
+//! Breakpoints and debug traps are reported and execution resumes. Every
+//! other exception is fatal: the handler records the registers of the
+//! interrupted code with `crash::set_context` and panics with the
+//! exception and its decoded error code, and the panic report shows
+//! those registers and a backtrace from the faulting instruction.
+
+use core::fmt;
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::{println, printsln};
+use crate::crash::{self, Registers};
+use super::idt::InterruptDescriptorTable;
+
+/// The error code pushed by exceptions which concern a segment selector:
+/// invalid TSS, segment not present, stack-segment and general protection faults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(pub u64);
+
+/// The descriptor table a `SelectorErrorCode` refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+impl fmt::Display for DescriptorTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DescriptorTable::Gdt => "GDT",
+            DescriptorTable::Idt => "IDT",
+            DescriptorTable::Ldt => "LDT",
+        })
+    }
+}
+
+impl SelectorErrorCode {
+    /// True if the exception was caused by an event external to the program,
+    /// such as a hardware interrupt.
+    pub fn external(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn table(self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b01 | 0b11 => DescriptorTable::Idt,
+            _ => DescriptorTable::Ldt,
+        }
+    }
+
+    /// The index of the descriptor within its table.
+    pub fn index(self) -> u16 {
+        ((self.0 >> 3) & 0x1FFF) as u16
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "{:#x} (no selector)", self.0);
+        }
+        write!(f, "{:#x} ({} index {:#x}", self.0, self.table(), self.index())?;
+        if self.external() {
+            write!(f, ", external")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Installs the handlers in this module.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    idt.divide_by_zero.set_handler_fn(divide_by_zero_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.security_exception.set_handler_fn(security_exception_handler);
+}
+
+/// Prints an exception report and the interrupted registers to VGA and serial.
+fn report(name: &str, registers: &Registers) {
+    println!("EXCEPTION: {}", name);
+    printsln!("EXCEPTION: {}", name);
+    crash::dump(registers);
+}
+
+fn fatal(name: &str, error_code: Option<&dyn fmt::Display>, registers: Registers) -> ! {
+    crash::set_context(registers);
+    match error_code {
+        Some(code) => panic!("EXCEPTION: {}, error code {}", name, code),
+        None => panic!("EXCEPTION: {}", name),
+    }
+}
+
+/// Defines a handler for a fatal exception. `selector` marks exceptions
+/// whose error code is a `SelectorErrorCode`, `code` those with any other.
+macro_rules! fatal_handler {
+    ($handler:ident, $name:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+            let registers = Registers::interrupted(&stack_frame);
+            fatal($name, None, registers)
+        }
+    };
+    ($handler:ident, $name:expr, selector) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+            let registers = Registers::interrupted(&stack_frame);
+            fatal($name, Some(&SelectorErrorCode(error_code)), registers)
+        }
+    };
+    ($handler:ident, $name:expr, code) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+            let registers = Registers::interrupted(&stack_frame);
+            fatal($name, Some(&format_args!("{:#x}", error_code)), registers)
+        }
+    };
+}
+
+fatal_handler!(divide_by_zero_handler, "DIVIDE BY ZERO");
+fatal_handler!(non_maskable_interrupt_handler, "NON-MASKABLE INTERRUPT");
+fatal_handler!(overflow_handler, "OVERFLOW");
+fatal_handler!(bound_range_exceeded_handler, "BOUND RANGE EXCEEDED");
+fatal_handler!(invalid_opcode_handler, "INVALID OPCODE");
+fatal_handler!(device_not_available_handler, "DEVICE NOT AVAILABLE");
+fatal_handler!(invalid_tss_handler, "INVALID TSS", selector);
+fatal_handler!(segment_not_present_handler, "SEGMENT NOT PRESENT", selector);
+fatal_handler!(stack_segment_fault_handler, "STACK-SEGMENT FAULT", selector);
+fatal_handler!(general_protection_fault_handler, "GENERAL PROTECTION FAULT", selector);
+fatal_handler!(x87_floating_point_handler, "x87 FLOATING POINT");
+fatal_handler!(alignment_check_handler, "ALIGNMENT CHECK", code);
+fatal_handler!(simd_floating_point_handler, "SIMD FLOATING POINT");
+fatal_handler!(virtualization_handler, "VIRTUALIZATION");
+fatal_handler!(security_exception_handler, "SECURITY EXCEPTION", code);
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    let registers = Registers::interrupted(&stack_frame);
+    fatal("MACHINE CHECK", None, registers)
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    let registers = Registers::interrupted(&stack_frame);
+    report("BREAKPOINT", &registers);
+}
+
+extern "x86-interrupt" fn debug_handler(mut stack_frame: InterruptStackFrame) {
+    use x86_64::registers::rflags::RFlags;
+
+    let registers = Registers::interrupted(&stack_frame);
+    report("DEBUG", &registers);
+    // Stop single-stepping, which would otherwise trap again right away, and
+    // set RF so an instruction breakpoint doesn't fire again on return.
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.cpu_flags = (frame.cpu_flags & !RFlags::TRAP_FLAG.bits())
+                | RFlags::RESUME_FLAG.bits();
+        });
+    }
+}
@@ -1,12 +1,15 @@
 //! Defines an Interrupt Descriptor table as well as code to initialize and fill it.
 
+pub mod apic;
+pub mod exceptions;
 mod idt;
 mod pic8259;
 pub mod serialkbd;
 
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
-use crate::{println, printsln, hlt_loop};
+use crate::crash::{self, Registers};
 use pic8259::ChainedPics;
 use spin;
 
@@ -34,7 +37,7 @@ lazy_static! {
     static ref IDT: idt::InterruptDescriptorTable = {
         let mut idt = idt::InterruptDescriptorTable::new();
         // CPU Exceptions
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        exceptions::install(&mut idt);
         idt.page_fault.set_handler_fn(page_fault_handler);
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
@@ -46,6 +49,12 @@ lazy_static! {
             .set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(keyboard_interrupt_handler);
+        idt[usize::from(PIC_1_OFFSET + 7)]
+            .set_handler_fn(pic_1_last_line_handler);
+        idt[usize::from(PIC_2_OFFSET + 7)]
+            .set_handler_fn(pic_2_last_line_handler);
+        idt[apic::SPURIOUS_VECTOR as usize]
+            .set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -74,16 +83,17 @@ impl InterruptIndex {
 }
 
 
-extern "x86-interrupt" fn breakpoint_handler(
-    stack_frame: InterruptStackFrame)
-{
-    printsln!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
+    use x86_64::registers::control::Cr2;
+
+    crash::set_context(Registers::interrupted(&stack_frame));
+    // Overflowing a stack faults on its guard page, and the page fault
+    // escalates because its frame can't be pushed onto the full stack.
+    if let Some(stack) = crate::memory::stack::guard_page_owner(Cr2::read()) {
+        panic!("EXCEPTION: STACK OVERFLOW in {}\n{:#?}", stack.name, stack_frame);
+    }
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
@@ -93,20 +103,58 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    let registers = Registers::interrupted(&stack_frame);
+    let addr = Cr2::read();
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && crate::memory::address_space::sync_kernel_slot(addr)
+    {
+        return;
+    }
+    let write_to_present = PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::PROTECTION_VIOLATION;
+    if error_code.contains(write_to_present) && crate::memory::cow::handle_write_fault(addr) {
+        return;
+    }
+    let user_mode = error_code.contains(PageFaultErrorCode::USER_MODE);
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && crate::memory::demand::handle_fault(addr, user_mode)
+    {
+        return;
+    }
+
+    // Like the other fatal exceptions, report through the panic handler.
+    crash::set_context(registers);
+    if let Some(stack) = crate::memory::stack::guard_page_owner(addr) {
+        panic!("EXCEPTION: STACK OVERFLOW in {}, accessed address {:#x}, error code {:?}\n{:#?}",
+            stack.name, addr.as_u64(), error_code, stack_frame);
+    }
+    let mapping = crate::memory::physical_memory_offset()
+        .and_then(|offset| unsafe { crate::memory::translate_addr(addr, offset) });
+    panic!("EXCEPTION: PAGE FAULT, accessed address {:#x}, error code {:?}\nMapping: {:?}\n{:#?}",
+        addr.as_u64(), error_code, mapping, stack_frame);
+}
+
+/// Signals the end of an interrupt to whichever controller delivered it.
+fn end_of_interrupt(index: InterruptIndex) {
+    if apic::enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) };
+    }
+}
+
+/// The number of timer interrupts since boot.
+static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of timer interrupts since boot.
+pub fn timer_ticks() -> u64 {
+    TIMER_TICKS.load(Ordering::Relaxed)
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
+    end_of_interrupt(InterruptIndex::Timer);
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(
@@ -118,10 +166,26 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode);
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+/// Spurious interrupts from the local APIC need no end of interrupt.
+extern "x86-interrupt" fn spurious_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+}
+
+/// IRQ 7 and IRQ 15 are unused, but the PICs raise them spuriously.
+extern "x86-interrupt" fn pic_1_last_line_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    unsafe { PICS.lock().notify_last_line(PIC_1_OFFSET + 7) };
+}
+
+extern "x86-interrupt" fn pic_2_last_line_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    unsafe { PICS.lock().notify_last_line(PIC_2_OFFSET + 7) };
 }
 
 
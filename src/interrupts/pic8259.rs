@@ -55,6 +55,9 @@ const CMD_INIT: u8 = 0x11;
 /// Command sent to acknowledge an interrupt.
 const CMD_END_OF_INTERRUPT: u8 = 0x20;
 
+/// Command sent to read the in-service register on the command port.
+const CMD_READ_ISR: u8 = 0x0B;
+
 // The mode in which we want to run our PICs.
 const MODE_8086: u8 = 0x01;
 
@@ -84,6 +87,12 @@ impl Pic {
         self.command.write(CMD_END_OF_INTERRUPT);
     }
 
+    /// Reads which of this PIC's interrupts are being serviced, one bit per line.
+    unsafe fn in_service(&mut self) -> u8 {
+        self.command.write(CMD_READ_ISR);
+        self.command.read()
+    }
+
     /// Reads the interrupt mask of this PIC.
     unsafe fn read_mask(&mut self) -> u8 {
         self.data.read()
@@ -200,4 +209,22 @@ impl ChainedPics {
             self.pics[0].end_of_interrupt();
         }
     }
+
+    /// Acknowledges an interrupt on the last line of either PIC, IRQ 7 or
+    /// IRQ 15, which a PIC also raises when an interrupt goes away before
+    /// the CPU acknowledges it.  Such a spurious interrupt is not in
+    /// service and must not get an end of interrupt, except that one from
+    /// PIC2 did arrive through PIC1's cascade line, which needs one.
+    pub unsafe fn notify_last_line(&mut self, interrupt_id: u8) {
+        let index = if self.pics[1].handles_interrupt(interrupt_id) { 1 } else { 0 };
+        let line = interrupt_id.wrapping_sub(self.pics[index].offset);
+        if line != 7 {
+            return;
+        }
+        if self.pics[index].in_service() & (1 << line) != 0 {
+            self.notify_end_of_interrupt(interrupt_id);
+        } else if index == 1 {
+            self.pics[0].end_of_interrupt();
+        }
+    }
 }
\ No newline at end of file
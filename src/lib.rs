@@ -25,11 +25,14 @@ entry_point!(test_kernel_main);
 
 pub mod serial;
 pub mod vga_buffer;
+pub mod crash;
 pub mod interrupts;
 pub mod segmentation;
 pub mod memory;
 pub mod task;
 pub mod initrd;
+pub mod acpi;
+pub mod power;
 
 /// Universal kernel initialization code.
 /// Separated into its own function so it may
@@ -71,6 +74,44 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     loop {}
 }
 
+/// The start of a panic message, formatted without allocating, so that
+/// tests which expect a panic can check what it said.
+pub struct PanicMessage {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl PanicMessage {
+    /// Formats `info`, keeping as many whole characters as fit.
+    pub fn new(info: &PanicInfo) -> Self {
+        use core::fmt::Write;
+
+        let mut message = PanicMessage { bytes: [0; 256], len: 0 };
+        let _ = write!(message, "{}", info);
+        message
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+
+    pub fn contains(&self, pattern: &str) -> bool {
+        self.as_str().contains(pattern)
+    }
+}
+
+impl core::fmt::Write for PanicMessage {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut n = s.len().min(self.bytes.len() - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum QemuExitCode {
@@ -108,6 +149,8 @@ fn panic(info: &PanicInfo) -> ! {
     test_panic_handler(info)
 }
 
+/// Only reached when the heap runs out outside a task, or when a task
+/// exhausts the emergency reserve as well (see `task::oom`).
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("allocation error: {:?}", layout)
@@ -31,15 +31,27 @@ fn kmain(boot_info: &'static BootInfo) -> ! {
     printsln!("Strike the Earth!");
 
     rust_os::init();
+    memory::set_boot_info(boot_info);
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::kernel_image::protect(&mut mapper)
+        .expect("failed to protect the kernel image");
     let mut frame_allocator = unsafe {
-        memory::BootInfoFrameAllocator::init(&boot_info.memory_map)
+        memory::BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
     };
 
     memory::allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    memory::stack::register_boot_stack();
+    rust_os::segmentation::init_stacks();
+
+    // Older machines, and QEMU's `isapc`, have no APIC: stay with the PICs there.
+    match rust_os::interrupts::apic::init() {
+        Ok(()) => printsln!("Interrupts: APIC"),
+        Err(error) => printsln!("Interrupts: 8259 PIC ({:?})", error),
+    }
 
     // If we're in test mode, run the test main.
     #[cfg(test)]
@@ -58,8 +70,7 @@ fn kmain(boot_info: &'static BootInfo) -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    printsln!("{}", info);
+    rust_os::crash::report(info);
     loop {}
 }
 
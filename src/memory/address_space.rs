@@ -0,0 +1,341 @@
+//! Per-process address spaces.
+//!
+//! Every `AddressSpace` has its own level 4 table. The kernel's entries
+//! (the first slot, which holds the kernel image, and the whole higher
+//! half) are copied from the kernel's table, so all address spaces share
+//! the kernel's lower level tables and see its mappings. The slots in
+//! between belong to the address space alone and hold user pages. The
+//! bootloader is configured to put everything else it maps, the boot
+//! information included, in the higher half; `AddressSpace::new` checks
+//! that it did.
+//!
+//! Higher-half slots the kernel starts using after an address space was
+//! created are missing from it. The page fault handler copies them in on
+//! first access through `sync_kernel_slot`.
+
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::{
+    mapper::{MapToError, UnmapError},
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB,
+};
+use alloc::vec::Vec;
+use crate::initrd::{MapFileError, MappedFile, INITRD, USTAR};
+use super::{
+    physical_memory_offset, kernel_level_4_frame, with_kernel_memory,
+    BuddyFrameAllocator, OffsetPageTable, PageTableWalker, Translation, BORROWED,
+};
+use super::cow::{self, CowError};
+use super::demand::{self, Backing, DemandError};
+use super::shm::{SharedMemory, SHARED};
+
+/// The lowest address available to user mappings: the start of
+/// the second level 4 slot, just above the kernel image.
+pub const USER_SPACE_START: u64 = 0x_0000_0080_0000_0000;
+
+/// One past the highest address available to user mappings:
+/// the end of the lower half.
+pub const USER_SPACE_END: u64 = 0x_0000_8000_0000_0000;
+
+/// The level 4 slots owned by each address space.
+const USER_SLOTS: core::ops::Range<usize> = 1..256;
+
+/// The level 4 slots shared with the kernel.
+fn kernel_slots() -> impl Iterator<Item = usize> {
+    core::iter::once(0).chain(256..512)
+}
+
+/// Returns true if `addr` lies in the part of an address space owned by user mappings.
+pub fn is_user_addr(addr: VirtAddr) -> bool {
+    (USER_SPACE_START..USER_SPACE_END).contains(&addr.as_u64())
+}
+
+/// A set of user mappings together with the shared kernel mappings.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with no user mappings.
+    /// Requires `memory::install`.
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        with_kernel_memory(|mapper, frames| {
+            let offset = physical_memory_offset().unwrap();
+            let kernel_table = mapper.level_4_table();
+            assert!(USER_SLOTS.clone().all(|index| kernel_table[index].is_unused()),
+                "the kernel has mappings in the user half");
+
+            let level_4_frame = allocate_table(frames)?;
+            let table = unsafe { table_at(level_4_frame, offset) };
+            for index in kernel_slots() {
+                table[index] = kernel_table[index].clone();
+            }
+            Ok(AddressSpace { level_4_frame })
+        })
+    }
+
+    /// The frame holding this address space's level 4 table.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Returns true if the CPU is currently using this address space.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Switches the CPU to this address space.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must keep this address space alive while it is active,
+    /// and must not rely on user mappings of the previous one.
+    pub unsafe fn activate(&self) {
+        Cr3::write(self.level_4_frame, Cr3Flags::empty());
+    }
+
+    /// Maps `page` to a fresh, zeroed frame accessible from user mode.
+    /// `flags` need not include `PRESENT` or `USER_ACCESSIBLE`.
+    pub fn map_user(&mut self, page: Page, flags: PageTableFlags) -> Result<PhysFrame, MapToError<Size4KiB>> {
+        with_kernel_memory(|_, frames| {
+            let frame = frames.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+            if let Err(error) = unsafe { self.map_with(page, frame, flags, frames) } {
+                unsafe { frames.deallocate_frame(frame) };
+                return Err(error);
+            }
+            Ok(frame)
+        })
+    }
+
+    /// Maps `page` to `frame`, which the address space takes ownership of:
+    /// it is handed back to the frame allocator on unmap or drop.
+    /// Use `cow::map_shared` for frames owned elsewhere too.
+    ///
+    /// ## Safety
+    ///
+    /// The frame must be unused by anything else.
+    pub unsafe fn map_to(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags)
+        -> Result<(), MapToError<Size4KiB>>
+    {
+        with_kernel_memory(|_, frames| self.map_with(page, frame, flags, frames))
+    }
+
+    /// Declares `[start, start + size)` as lazily backed: each page is mapped
+    /// with `flags` and filled from `backing` when it is first accessed.
+    pub fn map_lazy(&mut self, start: VirtAddr, size: u64, flags: PageTableFlags, backing: Backing)
+        -> Result<(), DemandError>
+    {
+        assert!(is_user_addr(start) && start.as_u64() + size <= USER_SPACE_END, "{:?} is not a user range", start);
+        let flags = flags | PageTableFlags::USER_ACCESSIBLE;
+        demand::declare(Some(self.level_4_frame), start, size, flags, backing)
+    }
+
+    /// Maps the initrd file `name` read-only and user accessible at `start`.
+    pub fn map_file(&mut self, name: &str, start: Page) -> Result<MappedFile, MapFileError> {
+        assert!(is_user_addr(start.start_address()), "{:?} is not a user page", start);
+        // Parsing the archive allocates, which must not happen with the frame allocator locked.
+        let initrd: &USTAR = &INITRD;
+        with_kernel_memory(|_, frames| {
+            let mut mapper = unsafe { self.mapper() };
+            initrd.map(name, start, PageTableFlags::USER_ACCESSIBLE, &mut mapper, frames)
+        })
+    }
+
+    /// Maps the shared memory object `object` at `start` with `flags`.
+    /// The mapping keeps the object's frames alive until it is unmapped
+    /// with `unmap_shared` or the address space is dropped.
+    pub fn map_shared(&mut self, object: &SharedMemory, start: Page, flags: PageTableFlags)
+        -> Result<(), MapToError<Size4KiB>>
+    {
+        let pages = object.pages() as u64;
+        assert!(is_user_addr(start.start_address()) && is_user_addr((start + (pages - 1)).start_address()),
+            "{:?} is not a user range", start);
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | SHARED;
+
+        let mut mapped = 0;
+        let result = with_kernel_memory(|_, frames| {
+            for &frame in object.frames() {
+                let mut mapper = unsafe { self.mapper() };
+                match unsafe { cow::map_shared(&mut mapper, frames, start + mapped, frame, flags) } {
+                    Ok(()) => mapped += 1,
+                    Err(CowError::Map(error)) => return Err(error),
+                    Err(error) => panic!("failed to map shared memory: {:?}", error),
+                }
+            }
+            Ok(())
+        });
+        if result.is_err() {
+            for page in Page::range(start, start + mapped) {
+                self.unmap(page).expect("page mapped just now");
+            }
+        }
+        result
+    }
+
+    /// Unmaps `pages` pages of shared memory starting at `start`,
+    /// giving up this address space's share of their frames.
+    pub fn unmap_shared(&mut self, start: Page, pages: u64) -> Result<(), UnmapError> {
+        for page in Page::range(start, start + pages) {
+            self.unmap(page)?;
+        }
+        Ok(())
+    }
+
+    /// Unmaps `page` and frees its frame, unless it is `BORROWED`.
+    pub fn unmap(&mut self, page: Page) -> Result<(), UnmapError> {
+        assert!(is_user_addr(page.start_address()), "{:?} is not a user page", page);
+        let active = self.is_active();
+        let borrowed = self.translate(page.start_address())
+            .map_or(false, |t| t.flags.contains(BORROWED));
+        with_kernel_memory(|_, frames| {
+            let (frame, flush) = unsafe { self.mapper() }.unmap(page)?;
+            if active {
+                flush.flush();
+            } else {
+                flush.ignore();
+            }
+            if !borrowed {
+                unsafe { frames.deallocate_frame(frame) };
+            }
+            Ok(())
+        })
+    }
+
+    /// Creates a copy of this address space whose user pages are
+    /// shared copy-on-write with this one. Lazily backed regions
+    /// are declared in the copy as well.
+    pub fn fork(&mut self) -> Result<AddressSpace, CowError> {
+        let mut child = AddressSpace::new()?;
+        demand::copy_space(self.level_4_frame, child.level_4_frame)?;
+        let offset = physical_memory_offset().unwrap();
+        let pages: Vec<Page> = unsafe { PageTableWalker::new(self.level_4_frame, offset) }
+            .mappings()
+            .filter(|m| is_user_addr(m.start))
+            .map(|m| Page::containing_address(m.start))
+            .collect();
+
+        with_kernel_memory(|_, frames| {
+            for page in pages {
+                let (frame, flags) = cow::mark_cow(&mut unsafe { self.mapper() }, page)?;
+                unsafe { cow::map_shared(&mut child.mapper(), frames, page, frame, flags)? };
+            }
+            Ok(child)
+        })
+    }
+
+    /// Translates an address through this address space.
+    pub fn translate(&self, addr: VirtAddr) -> Option<Translation> {
+        let offset = physical_memory_offset().unwrap();
+        unsafe { PageTableWalker::new(self.level_4_frame, offset) }.translate(addr)
+    }
+
+    unsafe fn map_with(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+        frames: &mut BuddyFrameAllocator,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(is_user_addr(page.start_address()), "{:?} is not a user page", page);
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let table_flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE;
+        let active = self.is_active();
+        let flush = self.mapper().map_to_with_table_flags(page, frame, flags, table_flags, frames)?;
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        Ok(())
+    }
+
+    /// A mapper for this address space's tables.
+    ///
+    /// ## Safety
+    ///
+    /// Only the user slots may be modified through it.
+    unsafe fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let offset = physical_memory_offset().unwrap();
+        OffsetPageTable::new(table_at(self.level_4_frame, offset), offset)
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees every user page and page table, then the level 4 table itself.
+    fn drop(&mut self) {
+        if self.is_active() {
+            let kernel = kernel_level_4_frame().unwrap();
+            unsafe { Cr3::write(kernel, Cr3Flags::empty()) };
+        }
+
+        let level_4_frame = self.level_4_frame;
+        demand::forget_space(level_4_frame);
+        with_kernel_memory(|_, frames| {
+            let offset = physical_memory_offset().unwrap();
+            let table = unsafe { table_at(level_4_frame, offset) };
+            for index in USER_SLOTS {
+                let entry = &table[index];
+                if entry.flags().contains(PageTableFlags::PRESENT) {
+                    unsafe { free_table(entry.addr(), 3, frames, offset) };
+                }
+            }
+            unsafe { frames.deallocate_frame(level_4_frame) };
+        });
+    }
+}
+
+/// Frees the table at `addr` at the given level (3 for a level 3 table),
+/// along with every table and frame below it.
+unsafe fn free_table(addr: PhysAddr, level: usize, frames: &mut BuddyFrameAllocator, offset: VirtAddr) {
+    let frame = PhysFrame::containing_address(addr);
+    for entry in table_at(frame, offset).iter() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        assert!(level == 1 || !flags.contains(PageTableFlags::HUGE_PAGE), "huge user pages are not supported");
+        if level == 1 {
+            if !flags.contains(BORROWED) {
+                frames.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(entry.addr()));
+            }
+        } else {
+            free_table(entry.addr(), level - 1, frames, offset);
+        }
+    }
+    frames.deallocate_frame(frame);
+}
+
+/// Copies the kernel's level 4 entry for `addr` into the active address
+/// space if it is missing there, returning true if it did. Called by the
+/// page fault handler, so that higher-half slots the kernel starts using
+/// after an address space was created appear in it on first access.
+pub fn sync_kernel_slot(addr: VirtAddr) -> bool {
+    let (offset, kernel) = match (physical_memory_offset(), kernel_level_4_frame()) {
+        (Some(offset), Some(kernel)) => (offset, kernel),
+        _ => return false,
+    };
+    let active = Cr3::read().0;
+    let index = usize::from(addr.p4_index());
+    if active == kernel || index < 256 {
+        return false;
+    }
+    let (table, kernel_table) = unsafe { (table_at(active, offset), table_at(kernel, offset)) };
+    if !table[index].is_unused() || kernel_table[index].is_unused() {
+        return false;
+    }
+    table[index] = kernel_table[index].clone();
+    true
+}
+
+/// Allocates an empty page table. The frame allocator zeroes it.
+fn allocate_table(frames: &mut BuddyFrameAllocator) -> Result<PhysFrame, MapToError<Size4KiB>> {
+    frames.allocate_frame().ok_or(MapToError::FrameAllocationFailed)
+}
+
+unsafe fn table_at(frame: PhysFrame, offset: VirtAddr) -> &'static mut PageTable {
+    &mut *(offset + frame.start_address().as_u64()).as_mut_ptr()
+}
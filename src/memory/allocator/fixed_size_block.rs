@@ -0,0 +1,113 @@
+//! A fixed-size-block (slab) allocator.
+//!
+//! Allocations are rounded up to one of a handful of size classes and
+//! served from a free list per class, making both allocation and
+//! deallocation O(1). Blocks are carved out of the linked list heap on
+//! first use and never returned to it. Anything larger than the biggest
+//! class goes straight to the linked list heap.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::ptr::{self, NonNull};
+use spin::Mutex;
+use super::GrowableHeap;
+
+/// The block sizes to use.
+///
+/// The sizes must each be power of 2 because they are also used as
+/// the block alignment (alignments must be always powers of 2).
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// A free block, linking to the next free block of the same size.
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// The free list heads, one per entry of `BLOCK_SIZES`.
+struct FreeLists {
+    heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+}
+
+pub struct FixedSizeBlockAllocator {
+    lists: Mutex<FreeLists>,
+    fallback: GrowableHeap,
+}
+
+impl FixedSizeBlockAllocator {
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            lists: Mutex::new(FreeLists {
+                heads: [EMPTY; BLOCK_SIZES.len()],
+            }),
+            fallback: GrowableHeap::empty(),
+        }
+    }
+
+    /// Initializes the fallback heap with the already mapped region `[start, start + size)`.
+    ///
+    /// ## Safety
+    ///
+    /// The region must be mapped, unused, and this must only be called once.
+    pub unsafe fn init(&self, start: usize, size: usize) {
+        self.fallback.init(start, size);
+    }
+
+    /// Returns the number of bytes currently mapped for the heap.
+    pub fn size(&self) -> usize {
+        self.fallback.size()
+    }
+}
+
+/// Chooses an appropriate block size for the given layout.
+///
+/// Returns an index into the `BLOCK_SIZES` array.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+unsafe impl GlobalAlloc for FixedSizeBlockAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let index = match list_index(&layout) {
+            Some(index) => index,
+            None => return self.fallback.alloc(layout),
+        };
+
+        let mut lists = self.lists.lock();
+        match lists.heads[index].take() {
+            Some(node) => {
+                lists.heads[index] = node.next.take();
+                node as *mut ListNode as *mut u8
+            }
+            None => {
+                // No block exists in the list, so carve a new one out
+                // of the fallback heap. Only works if all block sizes
+                // are a power of 2.
+                let block_size = BLOCK_SIZES[index];
+                let block_align = block_size;
+                let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                self.fallback.alloc(layout)
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let index = match list_index(&layout) {
+            Some(index) => index,
+            None => return self.fallback.dealloc(ptr, layout),
+        };
+
+        // Verify that the block has the size and alignment required for storing a node.
+        assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+        assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+
+        let mut lists = self.lists.lock();
+        let new_node = ListNode {
+            next: lists.heads[index].take(),
+        };
+        let new_node_ptr = NonNull::new_unchecked(ptr as *mut ListNode);
+        ptr::write(new_node_ptr.as_ptr(), new_node);
+        lists.heads[index] = Some(&mut *new_node_ptr.as_ptr());
+    }
+}
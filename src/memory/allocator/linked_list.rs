@@ -0,0 +1,83 @@
+//! The linked list heap, which doubles as the fallback
+//! for allocations too large for the fixed-size blocks.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use super::{heap_limit, map_heap_pages, HEAP_GROWTH_STEP};
+
+/// A linked list heap which maps more memory when it runs out.
+pub struct GrowableHeap {
+    heap: Mutex<Heap>,
+}
+
+impl GrowableHeap {
+    pub const fn empty() -> Self {
+        GrowableHeap {
+            heap: Mutex::new(Heap::empty()),
+        }
+    }
+
+    /// Initializes the heap with the already mapped region `[start, start + size)`.
+    ///
+    /// ## Safety
+    ///
+    /// The region must be mapped, unused, and this must only be called once.
+    pub unsafe fn init(&self, start: usize, size: usize) {
+        self.heap.lock().init(start, size);
+    }
+
+    /// Returns the number of bytes currently mapped for the heap.
+    pub fn size(&self) -> usize {
+        self.heap.lock().size()
+    }
+
+    /// Tries to extend the heap far enough to satisfy `layout`.
+    /// Returns false if the ceiling is reached or no memory could be mapped.
+    fn grow(heap: &mut Heap, layout: Layout) -> bool {
+        // Leave room for the worst-case alignment padding and round up to whole steps.
+        let wanted = layout.size() + layout.align();
+        let wanted = (wanted + HEAP_GROWTH_STEP - 1) / HEAP_GROWTH_STEP * HEAP_GROWTH_STEP;
+        let room = heap_limit().saturating_sub(heap.size());
+        let by = wanted.min(room) / 4096 * 4096;
+        if by == 0 {
+            return false;
+        }
+
+        // The heap lock is held here, so never block on the mapper: if the
+        // caller already holds it, growing would deadlock. Pages are mapped
+        // one at a time so that a partial success still extends the heap.
+        let top = heap.top();
+        let mapped = crate::memory::try_with_kernel_memory(|mapper, frames| {
+            let mut mapped = 0;
+            while mapped < by && map_heap_pages(top + mapped, 4096, mapper, frames).is_ok() {
+                mapped += 4096;
+            }
+            mapped
+        }).unwrap_or(0);
+
+        if mapped > 0 {
+            unsafe { heap.extend(mapped) };
+        }
+        mapped > 0
+    }
+}
+
+unsafe impl GlobalAlloc for GrowableHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.heap.lock();
+        loop {
+            if let Ok(ptr) = heap.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+            if !Self::grow(&mut heap, layout) {
+                return ptr::null_mut();
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.lock().deallocate(NonNull::new_unchecked(ptr), layout)
+    }
+}
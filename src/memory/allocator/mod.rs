@@ -0,0 +1,139 @@
+//! Contains heap memory allocator code.
+//!
+//! `init_heap` reserves `HEAP_MAX_SIZE` bytes of kernel virtual memory
+//! from the VMM and maps the first `HEAP_SIZE` bytes of it. When an
+//! allocation does not fit, more pages are mapped directly above the
+//! current top of the heap, up to the configured ceiling, and the
+//! allocation is retried before reporting the failure.
+//!
+//! By default the global allocator serves small allocations from
+//! per-size-class free lists and falls back to the linked list heap for
+//! anything larger. Building with the `linked-list-heap` feature uses
+//! the linked list heap for everything instead. Either way, usage is
+//! counted and reported by `stats()`, the `heap-redzones` feature adds
+//! overflow checks to every allocation (see `redzone`), and the executor
+//! can let tasks fall back on an emergency reserve (see `reserve`).
+
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+
+/// The upper bound on how far the heap may grow; this much
+/// virtual memory is reserved for it up front.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+
+/// The smallest amount the heap grows by at once, to avoid
+/// mapping a page at a time for a run of small allocations.
+const HEAP_GROWTH_STEP: usize = 64 * 1024; // 64 KiB
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+mod fixed_size_block;
+mod linked_list;
+mod redzone;
+mod reserve;
+mod stats;
+
+pub use fixed_size_block::FixedSizeBlockAllocator;
+pub use linked_list::GrowableHeap;
+pub use redzone::{Redzoned, REDZONE_BYTE};
+pub use reserve::{enable_reserve, reserve_in_use, take_exhaustion, Reserve, RESERVE_SIZE};
+pub use stats::{take_charge, HeapStats, Tracked, HISTOGRAM_BUCKETS};
+
+#[cfg(not(feature = "linked-list-heap"))]
+#[global_allocator]
+static ALLOCATOR: Tracked<Reserve<Redzoned<FixedSizeBlockAllocator>>> =
+    Tracked::new(Reserve::new(Redzoned::new(FixedSizeBlockAllocator::new())));
+
+#[cfg(feature = "linked-list-heap")]
+#[global_allocator]
+static ALLOCATOR: Tracked<Reserve<Redzoned<GrowableHeap>>> =
+    Tracked::new(Reserve::new(Redzoned::new(GrowableHeap::empty())));
+
+/// The current ceiling on the heap size in bytes.
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+/// Where the VMM placed the heap, or 0 before `init_heap`.
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+
+/// Reserves the heap's virtual region and maps its initial pages.
+/// Requires `memory::init`.
+pub fn init_heap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let start = crate::memory::vmm::reserve(HEAP_MAX_SIZE as u64, "kernel heap")
+        .expect("failed to reserve the kernel heap region")
+        .as_u64() as usize;
+    map_heap_pages(start, HEAP_SIZE, mapper, frame_allocator)?;
+
+    unsafe {
+        ALLOCATOR.inner().inner().inner().init(start, HEAP_SIZE);
+    }
+    HEAP_START.store(start, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// Sets the maximum size the heap may grow to, capped at `HEAP_MAX_SIZE`.
+/// The heap never shrinks, so a limit below the current size only
+/// prevents further growth.
+pub fn set_heap_limit(bytes: usize) {
+    HEAP_LIMIT.store(bytes.min(HEAP_MAX_SIZE), Ordering::Relaxed);
+}
+
+/// Returns the lowest address of the heap, or None before `init_heap`.
+pub fn heap_start() -> Option<VirtAddr> {
+    match HEAP_START.load(Ordering::Relaxed) {
+        0 => None,
+        start => Some(VirtAddr::new(start as u64)),
+    }
+}
+
+/// Returns the maximum size the heap may grow to.
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Returns the number of bytes currently mapped for the heap.
+pub fn heap_size() -> usize {
+    ALLOCATOR.inner().inner().inner().size()
+}
+
+/// Returns a snapshot of the heap usage counters.
+pub fn stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+/// Maps `size` bytes of fresh frames starting at `start`.
+fn map_heap_pages(
+    start: usize,
+    size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page_range = {
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size - 1u64;
+        let heap_start_page = Page::containing_address(heap_start);
+        let heap_end_page = Page::containing_address(heap_end);
+        Page::range_inclusive(heap_start_page, heap_end_page)
+    };
+
+    for page in page_range {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush()
+        };
+    }
+
+    Ok(())
+}
+
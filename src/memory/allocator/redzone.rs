@@ -0,0 +1,153 @@
+//! Heap redzones, enabled by the `heap-redzones` feature.
+//!
+//! `Redzoned` wraps the real allocator. With the feature enabled, every
+//! allocation is laid out as
+//!
+//! ```text
+//! | header | front redzone | data | back redzone |
+//! ```
+//!
+//! where the header records the requested size and the return addresses
+//! of the innermost frames at the time of the allocation, and both
+//! redzones are filled with `REDZONE_BYTE`. On free the header and the
+//! redzones are checked, and any damage is reported over serial along
+//! with the recorded call stack before the kernel panics.
+//!
+//! Without the feature, `Redzoned` passes everything straight through.
+//! The call stack is only meaningful when frame pointers are kept, which
+//! the target specification asks for.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
+use crate::crash::Location;
+use crate::printsln;
+
+/// The byte redzones are filled with.
+pub const REDZONE_BYTE: u8 = 0xFA;
+
+/// The minimum size of each redzone.
+const REDZONE_SIZE: usize = 16;
+
+/// The number of return addresses recorded per allocation.
+const CALLERS: usize = 6;
+
+/// The bookkeeping stored in front of every allocation.
+#[repr(C)]
+struct Header {
+    size: usize,
+    callers: [usize; CALLERS],
+}
+
+/// An allocator wrapper which surrounds allocations with redzones.
+pub struct Redzoned<A> {
+    inner: A,
+}
+
+impl<A> Redzoned<A> {
+    pub const fn new(inner: A) -> Self {
+        Redzoned { inner }
+    }
+
+    /// Returns the wrapped allocator.
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+/// The distance from the start of the real allocation to the data:
+/// the header and the front redzone, rounded up to the alignment.
+fn front(layout: Layout) -> usize {
+    let front = size_of::<Header>() + REDZONE_SIZE;
+    (front + layout.align() - 1) & !(layout.align() - 1)
+}
+
+/// The layout of the real allocation backing `layout`.
+fn padded(layout: Layout) -> Layout {
+    let size = front(layout) + layout.size() + REDZONE_SIZE;
+    Layout::from_size_align(size, layout.align().max(core::mem::align_of::<Header>())).unwrap()
+}
+
+/// Returns the return addresses of the innermost frames, found by
+/// following the saved frame pointers. Unused entries are zero.
+#[inline(always)]
+fn callers() -> [usize; CALLERS] {
+    let mut callers = [0; CALLERS];
+    let mut rbp: usize;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+
+    for caller in callers.iter_mut() {
+        if rbp == 0 || rbp % 8 != 0 {
+            break;
+        }
+        let (saved_rbp, return_address) = unsafe {
+            let frame = rbp as *const usize;
+            (frame.read(), frame.add(1).read())
+        };
+        *caller = return_address;
+        // Callers' frames lie above ours on the same stack.
+        if saved_rbp <= rbp || saved_rbp - rbp > 1024 * 1024 {
+            break;
+        }
+        rbp = saved_rbp;
+    }
+    callers
+}
+
+/// Reports a damaged allocation over serial and panics.
+fn report(data: *mut u8, layout: Layout, header: &Header, problem: core::fmt::Arguments) -> ! {
+    printsln!("heap corruption: {}", problem);
+    printsln!("  allocation {:p}, {} bytes, align {}", data, layout.size(), layout.align());
+    printsln!("  allocated from:");
+    for &caller in header.callers.iter().take_while(|&&c| c != 0) {
+        printsln!("    {:#x} {}", caller, Location::returning_to(caller as u64));
+    }
+    panic!("heap corruption at {:p}: {}", data, problem);
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Redzoned<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !cfg!(feature = "heap-redzones") {
+            return self.inner.alloc(layout);
+        }
+
+        let base = self.inner.alloc(padded(layout));
+        if base.is_null() {
+            return base;
+        }
+        let front = front(layout);
+        (base as *mut Header).write(Header { size: layout.size(), callers: callers() });
+        let header_end = base.add(size_of::<Header>());
+        core::ptr::write_bytes(header_end, REDZONE_BYTE, front - size_of::<Header>());
+        core::ptr::write_bytes(base.add(front + layout.size()), REDZONE_BYTE, REDZONE_SIZE);
+        base.add(front)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !cfg!(feature = "heap-redzones") {
+            return self.inner.dealloc(ptr, layout);
+        }
+
+        let front = front(layout);
+        let base = ptr.sub(front);
+        let header = &*(base as *const Header);
+        if header.size != layout.size() {
+            report(ptr, layout, header, format_args!(
+                "header overwritten: recorded size {} but freed with size {}", header.size, layout.size()));
+        }
+
+        let front_zone = core::slice::from_raw_parts(
+            base.add(size_of::<Header>()), front - size_of::<Header>());
+        if let Some(offset) = front_zone.iter().rposition(|&b| b != REDZONE_BYTE) {
+            report(ptr, layout, header, format_args!(
+                "underflow: byte {} before the allocation was overwritten",
+                front_zone.len() - offset));
+        }
+        let back_zone = core::slice::from_raw_parts(ptr.add(layout.size()), REDZONE_SIZE);
+        if let Some(offset) = back_zone.iter().position(|&b| b != REDZONE_BYTE) {
+            report(ptr, layout, header, format_args!(
+                "overflow: byte {} past the end of the allocation was overwritten", offset));
+        }
+
+        self.inner.dealloc(base, padded(layout))
+    }
+}
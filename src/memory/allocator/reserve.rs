@@ -0,0 +1,143 @@
+//! An emergency reserve for tasks which exhaust the heap.
+//!
+//! `Reserve` wraps the real allocator. While the reserve is enabled, which
+//! the executor does for the duration of each poll, an allocation the heap
+//! can't satisfy is served from a fixed arena in .bss instead, and the
+//! failure is recorded for `take_exhaustion`. The allocation succeeds, so
+//! the task carries on to its next `.await` like any other, and the
+//! executor can drop it there, running its destructors normally.
+//!
+//! The arena is a bump allocator which starts over once everything in it
+//! has been freed. It only has to cover what a task allocates between
+//! running out and returning to the executor; if it runs out too, the
+//! allocation fails and the allocation error handler panics.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// The size of the arena in bytes.
+pub const RESERVE_SIZE: usize = 64 * 1024; // 64 KiB
+
+#[repr(C, align(4096))]
+struct Arena(UnsafeCell<[u8; RESERVE_SIZE]>);
+
+// Handed out in disjoint pieces by `ARENA_STATE`.
+unsafe impl Sync for Arena {}
+
+static ARENA: Arena = Arena(UnsafeCell::new([0; RESERVE_SIZE]));
+
+/// The bump pointer as an offset into the arena, and the number of
+/// allocations in it which have not been freed yet.
+struct ArenaState {
+    next: usize,
+    live: usize,
+}
+
+static ARENA_STATE: Mutex<ArenaState> = Mutex::new(ArenaState { next: 0, live: 0 });
+
+/// Whether allocations may fall back on the arena.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Set when the arena served an allocation, along with the layout of
+/// the first one since the last `take_exhaustion`.
+static EXHAUSTED: AtomicBool = AtomicBool::new(false);
+static FAILED_SIZE: AtomicUsize = AtomicUsize::new(0);
+static FAILED_ALIGN: AtomicUsize = AtomicUsize::new(1);
+
+/// Allows or forbids allocations to fall back on the reserve.
+pub fn enable_reserve(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Returns the layout of the first allocation the heap could not satisfy
+/// since the previous call, if there was one, and clears the record.
+pub fn take_exhaustion() -> Option<Layout> {
+    if !EXHAUSTED.swap(false, Ordering::SeqCst) {
+        return None;
+    }
+    let size = FAILED_SIZE.load(Ordering::Relaxed);
+    let align = FAILED_ALIGN.load(Ordering::Relaxed);
+    Some(Layout::from_size_align(size, align).unwrap())
+}
+
+/// Returns the number of bytes of the reserve handed out and not yet reclaimed.
+pub fn reserve_in_use() -> usize {
+    x86_64::instructions::interrupts::without_interrupts(|| ARENA_STATE.lock().next)
+}
+
+fn arena_start() -> usize {
+    ARENA.0.get() as usize
+}
+
+fn arena_contains(ptr: *mut u8) -> bool {
+    (arena_start()..arena_start() + RESERVE_SIZE).contains(&(ptr as usize))
+}
+
+/// Allocates from the arena, or returns null if it is full.
+fn arena_alloc(layout: Layout) -> *mut u8 {
+    if layout.align() > 4096 {
+        return core::ptr::null_mut();
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut state = ARENA_STATE.lock();
+        let start = (arena_start() + state.next + layout.align() - 1) & !(layout.align() - 1);
+        let end = match start.checked_add(layout.size()) {
+            Some(end) if end <= arena_start() + RESERVE_SIZE => end,
+            _ => return core::ptr::null_mut(),
+        };
+        state.next = end - arena_start();
+        state.live += 1;
+        start as *mut u8
+    })
+}
+
+fn arena_dealloc() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut state = ARENA_STATE.lock();
+        state.live -= 1;
+        if state.live == 0 {
+            state.next = 0;
+        }
+    })
+}
+
+/// An allocator wrapper which falls back on the emergency reserve.
+pub struct Reserve<A> {
+    inner: A,
+}
+
+impl<A> Reserve<A> {
+    pub const fn new(inner: A) -> Self {
+        Reserve { inner }
+    }
+
+    /// Returns the wrapped allocator.
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Reserve<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() || !ENABLED.load(Ordering::SeqCst) {
+            return ptr;
+        }
+        let ptr = arena_alloc(layout);
+        if !ptr.is_null() && !EXHAUSTED.swap(true, Ordering::SeqCst) {
+            FAILED_SIZE.store(layout.size(), Ordering::Relaxed);
+            FAILED_ALIGN.store(layout.align(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if arena_contains(ptr) {
+            arena_dealloc();
+        } else {
+            self.inner.dealloc(ptr, layout);
+        }
+    }
+}
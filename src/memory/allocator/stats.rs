@@ -0,0 +1,147 @@
+//! Heap usage accounting.
+//!
+//! `Tracked` wraps the real allocator and keeps counters which can be read
+//! at any time through `memory::allocator::stats()`. With the `alloc-trace`
+//! feature every allocation and deallocation is also logged to serial.
+//!
+//! A separate running total, the charge, lets the executor attribute heap
+//! usage to tasks: it takes the charge before and after polling a task.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+
+/// Bytes allocated minus bytes freed since the last `take_charge`.
+static CHARGE: AtomicIsize = AtomicIsize::new(0);
+
+/// Returns the bytes allocated minus the bytes freed since the
+/// previous call, and starts counting from zero again.
+pub fn take_charge() -> isize {
+    CHARGE.swap(0, Ordering::Relaxed)
+}
+
+/// Number of histogram buckets. Bucket `i` counts allocations of
+/// at most `2^i` bytes; the last bucket counts everything larger.
+pub const HISTOGRAM_BUCKETS: usize = 16;
+
+/// A snapshot of the heap counters.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Allocations which have not been freed yet.
+    pub live_allocations: usize,
+    /// Bytes requested by live allocations.
+    pub bytes_in_use: usize,
+    /// The highest `bytes_in_use` seen so far.
+    pub peak_bytes_in_use: usize,
+    /// Allocations made since boot.
+    pub total_allocations: usize,
+    /// Allocations which returned null.
+    pub failed_allocations: usize,
+    /// Allocations made since boot, bucketed by size.
+    pub histogram: [usize; HISTOGRAM_BUCKETS],
+}
+
+impl HeapStats {
+    /// The largest size counted by histogram bucket `index`,
+    /// or None for the final, unbounded bucket.
+    pub fn bucket_limit(index: usize) -> Option<usize> {
+        if index + 1 < HISTOGRAM_BUCKETS {
+            Some(1 << index)
+        } else {
+            None
+        }
+    }
+}
+
+/// An allocator wrapper which counts what passes through it.
+pub struct Tracked<A> {
+    inner: A,
+    live_allocations: AtomicUsize,
+    bytes_in_use: AtomicUsize,
+    peak_bytes_in_use: AtomicUsize,
+    total_allocations: AtomicUsize,
+    failed_allocations: AtomicUsize,
+    histogram: [AtomicUsize; HISTOGRAM_BUCKETS],
+}
+
+impl<A> Tracked<A> {
+    pub const fn new(inner: A) -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicUsize = AtomicUsize::new(0);
+        Tracked {
+            inner,
+            live_allocations: ZERO,
+            bytes_in_use: ZERO,
+            peak_bytes_in_use: ZERO,
+            total_allocations: ZERO,
+            failed_allocations: ZERO,
+            histogram: [ZERO; HISTOGRAM_BUCKETS],
+        }
+    }
+
+    /// Returns the wrapped allocator.
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Returns a snapshot of the counters.
+    pub fn stats(&self) -> HeapStats {
+        let mut histogram = [0; HISTOGRAM_BUCKETS];
+        for (count, bucket) in histogram.iter_mut().zip(self.histogram.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+
+        HeapStats {
+            live_allocations: self.live_allocations.load(Ordering::Relaxed),
+            bytes_in_use: self.bytes_in_use.load(Ordering::Relaxed),
+            peak_bytes_in_use: self.peak_bytes_in_use.load(Ordering::Relaxed),
+            total_allocations: self.total_allocations.load(Ordering::Relaxed),
+            failed_allocations: self.failed_allocations.load(Ordering::Relaxed),
+            histogram,
+        }
+    }
+
+    fn record_alloc(&self, layout: Layout) {
+        self.live_allocations.fetch_add(1, Ordering::Relaxed);
+        self.total_allocations.fetch_add(1, Ordering::Relaxed);
+        let in_use = self.bytes_in_use.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        self.peak_bytes_in_use.fetch_max(in_use, Ordering::Relaxed);
+        self.histogram[bucket(layout.size())].fetch_add(1, Ordering::Relaxed);
+        CHARGE.fetch_add(layout.size() as isize, Ordering::Relaxed);
+    }
+
+    fn record_dealloc(&self, layout: Layout) {
+        self.live_allocations.fetch_sub(1, Ordering::Relaxed);
+        self.bytes_in_use.fetch_sub(layout.size(), Ordering::Relaxed);
+        CHARGE.fetch_sub(layout.size() as isize, Ordering::Relaxed);
+    }
+}
+
+/// Returns the histogram bucket for an allocation of `size` bytes.
+fn bucket(size: usize) -> usize {
+    let bits = usize::BITS - size.saturating_sub(1).leading_zeros();
+    (bits as usize).min(HISTOGRAM_BUCKETS - 1)
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Tracked<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if ptr.is_null() {
+            self.failed_allocations.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.record_alloc(layout);
+        }
+
+        #[cfg(feature = "alloc-trace")]
+        crate::printsln!("[alloc] {:p} size {} align {}", ptr, layout.size(), layout.align());
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "alloc-trace")]
+        crate::printsln!("[free]  {:p} size {} align {}", ptr, layout.size(), layout.align());
+
+        self.inner.dealloc(ptr, layout);
+        self.record_dealloc(layout);
+    }
+}
@@ -0,0 +1,188 @@
+//! A physical frame allocator which tracks every 4 KiB frame
+//! with a single bit, allowing frames to be freed and reused.
+//! Frames are zeroed when allocated; see `scrub`.
+
+use x86_64::{
+    VirtAddr,
+    PhysAddr,
+};
+use x86_64::structures::paging::{
+    FrameAllocator,
+    FrameDeallocator,
+    PhysFrame,
+    Size4KiB,
+};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use super::{scrub, FrameStats};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+
+/// A FrameAllocator backed by a bitmap stored in physical memory.
+///
+/// A set bit marks a frame as in use. The bitmap itself lives in
+/// the first usable region large enough to hold it and is reached
+/// through the complete physical memory mapping.
+pub struct BitmapFrameAllocator {
+    physical_memory_offset: VirtAddr,
+    bitmap: &'static mut [u64],
+    /// Number of usable frames in the memory map.
+    total: usize,
+    /// Number of frames currently available for allocation.
+    free: usize,
+    /// Index of the word to start searching from.
+    next: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Builds the allocator from the bootloader's memory map.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must guarantee that the memory map is valid, that all
+    /// `Usable` frames are really unused, and that the complete physical
+    /// memory is mapped at `physical_memory_offset`. This function must
+    /// only be called once, since the bitmap is placed in usable memory.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable = || memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        // The bitmap must cover every frame up to the end of the highest usable region.
+        let max_addr = usable().map(|r| r.range.end_addr()).max().unwrap_or(0);
+        let frames = (max_addr / FRAME_SIZE) as usize;
+        let words = (frames + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_bytes = (words * 8) as u64;
+        let bitmap_frames = (bitmap_bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        let bitmap_start = usable()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_frames * FRAME_SIZE)
+            .expect("no usable region is large enough for the frame bitmap")
+            .range.start_addr();
+
+        let ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(ptr, words);
+
+        // Everything starts out used, then usable regions are released.
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+
+        let mut allocator = BitmapFrameAllocator {
+            physical_memory_offset,
+            bitmap,
+            total: 0,
+            free: 0,
+            next: 0,
+        };
+
+        for region in usable() {
+            let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+            for index in start..end {
+                allocator.clear(index);
+            }
+            allocator.total += end - start;
+        }
+
+        // Reserve the frames the bitmap occupies.
+        let first = (bitmap_start / FRAME_SIZE) as usize;
+        for index in first..first + bitmap_frames as usize {
+            allocator.set(index);
+        }
+
+        allocator.free = allocator.total - bitmap_frames as usize;
+
+        if cfg!(feature = "frame-poison") {
+            for index in 0..frames {
+                if !allocator.is_set(index) {
+                    scrub::poison(index as u64 * FRAME_SIZE, 1, physical_memory_offset);
+                }
+            }
+        }
+        allocator
+    }
+
+    /// Returns the number of usable frames managed by this allocator.
+    pub fn total_frames(&self) -> usize {
+        self.total
+    }
+
+    /// Returns the number of frames available for allocation.
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    /// Returns the number of frames currently handed out,
+    /// including the frames holding the bitmap.
+    pub fn used_frames(&self) -> usize {
+        self.total - self.free
+    }
+
+    /// Returns a snapshot of the allocator's frame counts.
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total_frames: self.total,
+            free_frames: self.free,
+        }
+    }
+
+    /// Returns true if the frame at the given index is in use.
+    fn is_set(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn clear(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free == 0 {
+            return None;
+        }
+
+        // Scan whole words at a time, starting at the hint
+        // and wrapping around to the start of the bitmap.
+        let words = self.bitmap.len();
+        for offset in 0..words {
+            let word_index = (self.next + offset) % words;
+            let word = self.bitmap[word_index];
+            if word == !0 {
+                continue;
+            }
+
+            let index = word_index * BITS_PER_WORD + (!word).trailing_zeros() as usize;
+            self.set(index);
+            self.free -= 1;
+            self.next = word_index;
+            let addr = index as u64 * FRAME_SIZE;
+            unsafe {
+                scrub::check_poison(addr, 1, self.physical_memory_offset);
+                scrub::zero(addr, 1, self.physical_memory_offset);
+            }
+            return Some(PhysFrame::containing_address(PhysAddr::new(addr)));
+        }
+
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(index < self.bitmap.len() * BITS_PER_WORD, "frame {:?} is outside the bitmap", frame);
+        assert!(self.is_set(index), "frame {:?} freed twice", frame);
+        scrub::poison(frame.start_address().as_u64(), 1, self.physical_memory_offset);
+
+        self.clear(index);
+        self.free += 1;
+        if index / BITS_PER_WORD < self.next {
+            self.next = index / BITS_PER_WORD;
+        }
+    }
+}
@@ -0,0 +1,351 @@
+//! A binary buddy allocator for physical frames, capable of handing
+//! out naturally aligned 4 KiB, 2 MiB and 1 GiB frames.
+//!
+//! Free blocks are kept on one intrusive, doubly linked list per order.
+//! The list links live inside the free blocks themselves and are reached
+//! through the complete physical memory mapping, so the allocator needs
+//! no heap. A byte per frame records the order of each free block head,
+//! which lets a freed block find and merge with its buddy in O(1).
+//!
+//! Allocated blocks can have several owners, for pages shared between
+//! mappings. A block is only released once every owner has freed it.
+//!
+//! Blocks are zeroed when allocated; see `scrub`.
+
+use x86_64::{
+    VirtAddr,
+    PhysAddr,
+};
+use x86_64::structures::paging::{
+    FrameAllocator,
+    FrameDeallocator,
+    PageSize,
+    PhysFrame,
+    Size1GiB,
+    Size2MiB,
+    Size4KiB,
+};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use super::{scrub, FrameStats};
+
+const FRAME_SIZE: u64 = 4096;
+
+/// The largest block order, 2^18 frames or 1 GiB.
+pub const MAX_ORDER: usize = 18;
+
+/// Marks a frame which is not the head of a free block.
+const NOT_FREE: u8 = 0xFF;
+
+/// Marks the end of a free list.
+const NIL: u64 = u64::MAX;
+
+/// The links stored at the start of every free block.
+#[repr(C)]
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+/// Why `try_deallocate_block` refused to free a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreeError {
+    /// The order is larger than `MAX_ORDER`.
+    OrderTooLarge,
+    /// The address is not aligned to the block size.
+    Misaligned,
+    /// The block extends past the memory this allocator manages.
+    OutOfRange,
+    /// The block is free already, or lies inside a free block.
+    AlreadyFree,
+}
+
+/// A FrameAllocator which splits and coalesces power-of-two blocks of frames.
+pub struct BuddyFrameAllocator {
+    physical_memory_offset: VirtAddr,
+    /// Physical address of the first free block of each order.
+    free_lists: [u64; MAX_ORDER + 1],
+    /// The order of the free block starting at each frame, or `NOT_FREE`.
+    orders: &'static mut [u8],
+    /// The number of owners beyond the first of the allocated block
+    /// starting at each frame.
+    shares: &'static mut [u16],
+    /// Number of usable frames in the memory map.
+    total: usize,
+    /// Number of frames currently available for allocation.
+    free: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// Builds the allocator from the bootloader's memory map.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must guarantee that the memory map is valid, that all
+    /// `Usable` frames are really unused, and that the complete physical
+    /// memory is mapped at `physical_memory_offset`. This function must
+    /// only be called once, since its bookkeeping is placed in usable memory.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable = || memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        // Three bytes of bookkeeping per frame up to the highest usable
+        // address: the order map followed by the (aligned) share counts.
+        let max_addr = usable().map(|r| r.range.end_addr()).max().unwrap_or(0);
+        let frames = (max_addr / FRAME_SIZE) as usize;
+        let shares_offset = (frames as u64 + 1) & !1;
+        let map_size = shares_offset + frames as u64 * 2;
+        let map_size = (map_size + FRAME_SIZE - 1) / FRAME_SIZE * FRAME_SIZE;
+
+        let map_start = usable()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= map_size)
+            .expect("no usable region is large enough for the buddy order map")
+            .range.start_addr();
+        let map_end = map_start + map_size;
+
+        let ptr: *mut u8 = (physical_memory_offset + map_start).as_mut_ptr();
+        let orders = core::slice::from_raw_parts_mut(ptr, frames);
+        for order in orders.iter_mut() {
+            *order = NOT_FREE;
+        }
+        let ptr: *mut u16 = (physical_memory_offset + map_start + shares_offset).as_mut_ptr();
+        let shares = core::slice::from_raw_parts_mut(ptr, frames);
+        for share in shares.iter_mut() {
+            *share = 0;
+        }
+
+        let mut allocator = BuddyFrameAllocator {
+            physical_memory_offset,
+            free_lists: [NIL; MAX_ORDER + 1],
+            orders,
+            shares,
+            total: 0,
+            free: 0,
+        };
+
+        for region in usable() {
+            let (start, end) = (region.range.start_addr(), region.range.end_addr());
+            allocator.total += ((end - start) / FRAME_SIZE) as usize;
+
+            // Release everything except the order map itself.
+            if map_end <= start || end <= map_start {
+                allocator.add_range(start, end);
+            } else {
+                allocator.add_range(start, map_start.max(start));
+                allocator.add_range(map_end.min(end), end);
+            }
+        }
+
+        allocator
+    }
+
+    /// Returns the number of usable frames managed by this allocator.
+    pub fn total_frames(&self) -> usize {
+        self.total
+    }
+
+    /// Returns the number of 4 KiB frames available for allocation.
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    /// Returns the number of 4 KiB frames currently handed out,
+    /// including the frames holding the order map.
+    pub fn used_frames(&self) -> usize {
+        self.total - self.free
+    }
+
+    /// Returns a snapshot of the allocator's frame counts.
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total_frames: self.total,
+            free_frames: self.free,
+        }
+    }
+
+    /// Allocates a naturally aligned, zeroed block of `2^order` frames.
+    pub fn allocate_block(&mut self, order: usize) -> Option<PhysAddr> {
+        assert!(order <= MAX_ORDER, "block order {} is too large", order);
+
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != NIL)?;
+        let addr = self.free_lists[current];
+        unsafe { self.remove(addr, current) };
+
+        // Split the block, returning the upper halves to their free lists.
+        while current > order {
+            current -= 1;
+            unsafe { self.push(addr + (FRAME_SIZE << current), current) };
+        }
+
+        self.free -= 1 << order;
+        unsafe {
+            scrub::check_poison(addr, 1 << order, self.physical_memory_offset);
+            scrub::zero(addr, 1 << order, self.physical_memory_offset);
+        }
+        Some(PhysAddr::new(addr))
+    }
+
+    /// Frees a block of `2^order` frames, merging it with its buddies.
+    /// If the block has other owners, only this owner's share is dropped.
+    /// Panics if the block can't be freed; see `try_deallocate_block`.
+    ///
+    /// ## Safety
+    ///
+    /// The block must have been allocated from this allocator, or be part
+    /// of a block that was, and must no longer be in use by this owner.
+    pub unsafe fn deallocate_block(&mut self, addr: PhysAddr, order: usize) {
+        if let Err(error) = self.try_deallocate_block(addr, order) {
+            panic!("cannot free block {:#x} of order {}: {:?}", addr.as_u64(), order, error);
+        }
+    }
+
+    /// Like `deallocate_block`, but returns an error instead of panicking
+    /// for a block which is out of range, misaligned or already free.
+    ///
+    /// ## Safety
+    ///
+    /// As for `deallocate_block`.
+    pub unsafe fn try_deallocate_block(&mut self, addr: PhysAddr, order: usize) -> Result<(), FreeError> {
+        let addr = addr.as_u64();
+        if order > MAX_ORDER {
+            return Err(FreeError::OrderTooLarge);
+        }
+        if addr % (FRAME_SIZE << order) != 0 {
+            return Err(FreeError::Misaligned);
+        }
+        let index = self.index(addr);
+        if index + (1 << order) > self.orders.len() {
+            return Err(FreeError::OutOfRange);
+        }
+        if self.free_block_containing(addr).is_some() {
+            return Err(FreeError::AlreadyFree);
+        }
+
+        if self.shares[index] > 0 {
+            self.shares[index] -= 1;
+            return Ok(());
+        }
+        scrub::poison(addr, 1 << order, self.physical_memory_offset);
+        self.release(addr, order);
+        self.free += 1 << order;
+        Ok(())
+    }
+
+    /// Adds an owner to the allocated block starting at `addr`, so that it
+    /// stays allocated until one more `deallocate_block` than before.
+    pub fn share_block(&mut self, addr: PhysAddr) {
+        let index = self.index(addr.as_u64());
+        assert!(index < self.orders.len(), "shared block {:#x} is out of range", addr.as_u64());
+        assert!(self.free_block_containing(addr.as_u64()).is_none(), "shared block {:#x} is free", addr.as_u64());
+        self.shares[index] = self.shares[index].checked_add(1)
+            .expect("too many owners of one block");
+    }
+
+    /// Returns the number of owners of the allocated block starting at `addr`.
+    pub fn owners(&self, addr: PhysAddr) -> usize {
+        self.shares[self.index(addr.as_u64())] as usize + 1
+    }
+
+    /// Returns freshly usable memory in `[start, end)` to the free lists
+    /// as the largest aligned blocks that fit.
+    unsafe fn add_range(&mut self, start: u64, end: u64) {
+        scrub::poison(start, (end - start) / FRAME_SIZE, self.physical_memory_offset);
+        let mut addr = start;
+        while addr < end {
+            let mut order = MAX_ORDER;
+            while addr % (FRAME_SIZE << order) != 0 || addr + (FRAME_SIZE << order) > end {
+                order -= 1;
+            }
+            self.release(addr, order);
+            self.free += 1 << order;
+            addr += FRAME_SIZE << order;
+        }
+    }
+
+    /// Pushes a block onto the free lists, coalescing with free buddies.
+    unsafe fn release(&mut self, mut addr: u64, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = addr ^ (FRAME_SIZE << order);
+            let buddy_index = self.index(buddy);
+            if buddy_index >= self.orders.len() || self.orders[buddy_index] != order as u8 {
+                break;
+            }
+            self.remove(buddy, order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+
+    /// Returns the head of the free block containing the frame at `addr`,
+    /// if there is one. Only the aligned block of each order can contain it.
+    fn free_block_containing(&self, addr: u64) -> Option<u64> {
+        (0..=MAX_ORDER).find_map(|order| {
+            let head = addr & !((FRAME_SIZE << order) - 1);
+            match self.orders.get(self.index(head)) {
+                Some(&o) if o == order as u8 => Some(head),
+                _ => None,
+            }
+        })
+    }
+
+    fn index(&self, addr: u64) -> usize {
+        (addr / FRAME_SIZE) as usize
+    }
+
+    fn block(&self, addr: u64) -> *mut FreeBlock {
+        (self.physical_memory_offset + addr).as_mut_ptr()
+    }
+
+    /// Links a block in at the head of its free list.
+    unsafe fn push(&mut self, addr: u64, order: usize) {
+        let head = self.free_lists[order];
+        *self.block(addr) = FreeBlock { next: head, prev: NIL };
+        if head != NIL {
+            (*self.block(head)).prev = addr;
+        }
+        self.free_lists[order] = addr;
+        let index = self.index(addr);
+        self.orders[index] = order as u8;
+    }
+
+    /// Unlinks a block from its free list.
+    unsafe fn remove(&mut self, addr: u64, order: usize) {
+        let FreeBlock { next, prev } = self.block(addr).read();
+        if prev == NIL {
+            self.free_lists[order] = next;
+        } else {
+            (*self.block(prev)).next = next;
+        }
+        if next != NIL {
+            (*self.block(next)).prev = prev;
+        }
+        let index = self.index(addr);
+        self.orders[index] = NOT_FREE;
+    }
+}
+
+/// Returns the buddy order holding exactly one frame of size `S`.
+fn order_of<S: PageSize>() -> usize {
+    (S::SIZE / FRAME_SIZE).trailing_zeros() as usize
+}
+
+macro_rules! impl_frame_traits {
+    ($size:ty) => {
+        unsafe impl FrameAllocator<$size> for BuddyFrameAllocator {
+            fn allocate_frame(&mut self) -> Option<PhysFrame<$size>> {
+                let addr = self.allocate_block(order_of::<$size>())?;
+                Some(PhysFrame::containing_address(addr))
+            }
+        }
+
+        impl FrameDeallocator<$size> for BuddyFrameAllocator {
+            unsafe fn deallocate_frame(&mut self, frame: PhysFrame<$size>) {
+                self.deallocate_block(frame.start_address(), order_of::<$size>())
+            }
+        }
+    };
+}
+
+impl_frame_traits!(Size4KiB);
+impl_frame_traits!(Size2MiB);
+impl_frame_traits!(Size1GiB);
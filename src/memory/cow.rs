@@ -0,0 +1,179 @@
+//! Copy-on-write pages.
+//!
+//! A page shared copy-on-write is mapped read-only with the `COW` software
+//! bit set, and its frame gets one more owner in the frame allocator. The
+//! first write to it faults, and `handle_write_fault` either gives the
+//! writer a private copy of the frame or, if it is the last owner, simply
+//! makes the page writable again. `BORROWED` frames are not the kernel's
+//! to write to or free, so writers always get a copy of them.
+
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{
+    mapper::{MapToError, MappedFrame, TranslateResult},
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use super::demand::DemandError;
+use super::shm::SHARED;
+use super::{
+    active_level_4_table, physical_memory_offset, BuddyFrameAllocator, OffsetPageTable,
+    BORROWED, FRAME_ALLOCATOR,
+};
+
+/// Marks a page as copy-on-write. One of the bits left to the OS.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+#[derive(Debug)]
+pub enum CowError {
+    /// The page is not mapped.
+    PageNotMapped,
+    /// The page is part of a huge page, which cannot be shared.
+    HugePage,
+    /// The page is mapped but is not copy-on-write.
+    NotCow,
+    /// Mapping the page failed.
+    Map(MapToError<Size4KiB>),
+    /// Lazily backed regions could not be declared in a forked address space.
+    Demand(DemandError),
+}
+
+impl From<MapToError<Size4KiB>> for CowError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        CowError::Map(error)
+    }
+}
+
+impl From<DemandError> for CowError {
+    fn from(error: DemandError) -> Self {
+        CowError::Demand(error)
+    }
+}
+
+/// Maps `to` to the frame behind `from`, so that both share it
+/// copy-on-write. Read-only pages are shared as they are.
+pub fn share_page<M>(
+    mapper: &mut M,
+    frames: &mut BuddyFrameAllocator,
+    from: Page,
+    to: Page,
+) -> Result<(), CowError>
+where
+    M: Mapper<Size4KiB> + Translate,
+{
+    let (frame, flags) = mark_cow(mapper, from)?;
+    unsafe { map_shared(mapper, frames, to, frame, flags) }
+}
+
+/// Makes a page copy-on-write if it is writable, returning
+/// its frame and the flags a second mapping of it should use.
+/// Shared memory stays writable and shared.
+pub fn mark_cow<M>(mapper: &mut M, page: Page) -> Result<(PhysFrame, PageTableFlags), CowError>
+where
+    M: Mapper<Size4KiB> + Translate,
+{
+    let (frame, flags) = translate_page(mapper, page)?;
+    if !flags.contains(PageTableFlags::WRITABLE) || flags.contains(SHARED) {
+        return Ok((frame, flags));
+    }
+
+    let flags = (flags - PageTableFlags::WRITABLE) | COW;
+    unsafe { mapper.update_flags(page, flags) }
+        .expect("page translated just now")
+        .flush();
+    Ok((frame, flags))
+}
+
+/// Maps `page` to `frame` as one more owner of it.
+/// `BORROWED` frames are mapped without taking ownership.
+///
+/// ## Safety
+///
+/// `frame` must be allocated from `frames` (or be `BORROWED`) and mapped
+/// by its other owners with the same flags, as returned by `mark_cow`.
+pub unsafe fn map_shared(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frames: &mut BuddyFrameAllocator,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), CowError> {
+    let table_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (flags & PageTableFlags::USER_ACCESSIBLE);
+    mapper.map_to_with_table_flags(page, frame, flags, table_flags, frames)?.flush();
+    if !flags.contains(BORROWED) {
+        frames.share_block(frame.start_address());
+    }
+    Ok(())
+}
+
+/// Gives `page` a private, writable copy of its copy-on-write frame.
+///
+/// ## Safety
+///
+/// The complete physical memory must be mapped at `physical_memory_offset`.
+pub unsafe fn break_cow<M>(
+    mapper: &mut M,
+    frames: &mut BuddyFrameAllocator,
+    page: Page,
+    physical_memory_offset: VirtAddr,
+) -> Result<(), CowError>
+where
+    M: Mapper<Size4KiB> + Translate,
+{
+    let (frame, flags) = translate_page(mapper, page)?;
+    if !flags.contains(COW) {
+        return Err(CowError::NotCow);
+    }
+    let writable = (flags | PageTableFlags::WRITABLE) - COW - BORROWED;
+
+    // The last owner can keep the frame. A borrowed frame belongs to
+    // someone else, so it is always copied and never freed.
+    let borrowed = flags.contains(BORROWED);
+    if !borrowed && frames.owners(frame.start_address()) == 1 {
+        mapper.update_flags(page, writable).expect("page translated just now").flush();
+        return Ok(());
+    }
+
+    let copy: PhysFrame = frames.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+    let src: *const u8 = (physical_memory_offset + frame.start_address().as_u64()).as_ptr();
+    let dst: *mut u8 = (physical_memory_offset + copy.start_address().as_u64()).as_mut_ptr();
+    core::ptr::copy_nonoverlapping(src, dst, 4096);
+
+    let (_, flush) = mapper.unmap(page).expect("page translated just now");
+    flush.ignore();
+    mapper.map_to(page, copy, writable, frames)?.flush();
+    if !borrowed {
+        frames.deallocate_frame(frame);
+    }
+    Ok(())
+}
+
+/// Resolves a write fault at `addr` in the active address space.
+/// Returns false if `addr` is not on a copy-on-write page, or if the
+/// frame allocator is held by the interrupted code.
+pub fn handle_write_fault(addr: VirtAddr) -> bool {
+    let offset = match physical_memory_offset() {
+        Some(offset) => offset,
+        None => return false,
+    };
+    let mut frames = match FRAME_ALLOCATOR.try_lock() {
+        Some(frames) => frames,
+        None => return false,
+    };
+    let frames = match frames.as_mut() {
+        Some(frames) => frames,
+        None => return false,
+    };
+
+    let mut mapper = unsafe { OffsetPageTable::new(active_level_4_table(offset), offset) };
+    let page = Page::containing_address(addr);
+    unsafe { break_cow(&mut mapper, frames, page, offset) }.is_ok()
+}
+
+fn translate_page<M: Translate>(mapper: &M, page: Page) -> Result<(PhysFrame, PageTableFlags), CowError> {
+    match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => Ok((frame, flags)),
+        TranslateResult::Mapped { .. } => Err(CowError::HugePage),
+        _ => Err(CowError::PageNotMapped),
+    }
+}
@@ -0,0 +1,220 @@
+//! Demand paging.
+//!
+//! Regions declared here are not backed by memory up front. The first
+//! access to each of their pages faults, and `handle_fault` maps a frame
+//! filled from the region's backing, after which execution resumes.
+//!
+//! Regions belong either to the kernel, in which case they are visible
+//! in every address space, or to the user half of one address space.
+//! The table has a fixed size so that the fault handler never allocates.
+
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+    PhysFrame, Size4KiB,
+};
+use super::address_space::sync_kernel_slot;
+use super::{
+    active_level_4_table, physical_memory_offset, try_with_kernel_memory, BuddyFrameAllocator, OffsetPageTable,
+    FRAME_ALLOCATOR,
+};
+
+/// The maximum number of lazily backed regions across all address spaces.
+const MAX_LAZY_REGIONS: usize = 64;
+
+/// Where the contents of a lazily backed page come from.
+#[derive(Debug, Clone, Copy)]
+pub enum Backing {
+    /// Every page starts out zeroed.
+    Zero,
+    /// Page `n` holds bytes `n * 4096..(n + 1) * 4096` of the data,
+    /// zero-filled past its end.
+    File(&'static [u8]),
+}
+
+/// A range of virtual memory populated on first access.
+#[derive(Debug, Clone, Copy)]
+pub struct LazyRegion {
+    pub start: VirtAddr,
+    pub size: u64,
+    /// The flags each page is mapped with.
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+    /// The level 4 table of the owning address space, or None for the kernel.
+    space: Option<PhysFrame>,
+}
+
+impl LazyRegion {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.start + self.size
+    }
+
+    fn visible_in(&self, space: PhysFrame) -> bool {
+        self.space.map_or(true, |s| s == space)
+    }
+}
+
+#[derive(Debug)]
+pub enum DemandError {
+    /// The range overlaps a region already declared in the same address space.
+    Overlap,
+    /// The region table is full.
+    TooManyRegions,
+}
+
+static REGIONS: Mutex<[Option<LazyRegion>; MAX_LAZY_REGIONS]> = Mutex::new([None; MAX_LAZY_REGIONS]);
+
+/// Declares a lazily backed kernel region. The range must already be
+/// reserved from the VMM; `vmm::reserve_lazy` does both.
+pub fn declare_kernel(start: VirtAddr, size: u64, flags: PageTableFlags, backing: Backing)
+    -> Result<(), DemandError>
+{
+    declare(None, start, size, flags, backing)
+}
+
+/// Declares a lazily backed region, in one address space if `space` is given.
+pub(crate) fn declare(
+    space: Option<PhysFrame>,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    backing: Backing,
+) -> Result<(), DemandError> {
+    assert!(start.is_aligned(4096u64), "lazy region {:?} is not page aligned", start);
+    let size = (size + 4095) / 4096 * 4096;
+    let region = LazyRegion { start, size, flags: flags | PageTableFlags::PRESENT, backing, space };
+
+    let mut regions = REGIONS.lock();
+    let overlaps = |r: &LazyRegion| r.space == space
+        && r.start < start + size
+        && start < r.start + r.size;
+    if regions.iter().flatten().any(overlaps) {
+        return Err(DemandError::Overlap);
+    }
+    let slot = regions.iter_mut()
+        .find(|r| r.is_none())
+        .ok_or(DemandError::TooManyRegions)?;
+    *slot = Some(region);
+    Ok(())
+}
+
+/// Removes the region starting at `start`. Pages already populated stay mapped.
+pub(crate) fn forget(space: Option<PhysFrame>, start: VirtAddr) -> Option<LazyRegion> {
+    REGIONS.lock().iter_mut()
+        .find(|r| matches!(r, Some(r) if r.space == space && r.start == start))?
+        .take()
+}
+
+/// Removes every region of an address space which is going away.
+pub(crate) fn forget_space(space: PhysFrame) {
+    for slot in REGIONS.lock().iter_mut() {
+        if matches!(slot, Some(r) if r.space == Some(space)) {
+            *slot = None;
+        }
+    }
+}
+
+/// Declares every region of `parent` in `child` as well.
+pub(crate) fn copy_space(parent: PhysFrame, child: PhysFrame) -> Result<(), DemandError> {
+    let mut regions = REGIONS.lock();
+    for index in 0..MAX_LAZY_REGIONS {
+        let region = match regions[index] {
+            Some(region) if region.space == Some(parent) => region,
+            _ => continue,
+        };
+        let slot = regions.iter_mut()
+            .find(|r| r.is_none())
+            .ok_or(DemandError::TooManyRegions)?;
+        *slot = Some(LazyRegion { space: Some(child), ..region });
+    }
+    Ok(())
+}
+
+/// Populates the page containing `addr` if it lies in a lazily backed region
+/// of the active address space. Returns false if it does not, if a user mode
+/// access hit a kernel page, or if the frame allocator is held by the
+/// interrupted code.
+pub fn handle_fault(addr: VirtAddr, user_mode: bool) -> bool {
+    let offset = match physical_memory_offset() {
+        Some(offset) => offset,
+        None => return false,
+    };
+    let space = Cr3::read().0;
+    let region = match REGIONS.try_lock() {
+        Some(regions) => regions.iter()
+            .flatten()
+            .find(|r| r.visible_in(space) && r.contains(addr))
+            .copied(),
+        None => return false,
+    };
+    let region = match region {
+        Some(region) if !user_mode || region.flags.contains(PageTableFlags::USER_ACCESSIBLE) => region,
+        _ => return false,
+    };
+
+    let page = Page::containing_address(addr);
+    if region.space.is_none() {
+        // Kernel pages go into the kernel's own tables, which every address
+        // space shares, and the active one gets their level 4 entry from there.
+        let populated = try_with_kernel_memory(|mapper, frames| unsafe {
+            populate(mapper, frames, &region, page, offset)
+        });
+        if !matches!(populated, Some(Ok(()))) {
+            return false;
+        }
+        sync_kernel_slot(addr);
+        return true;
+    }
+
+    let mut frames = match FRAME_ALLOCATOR.try_lock() {
+        Some(frames) => frames,
+        None => return false,
+    };
+    let frames = match frames.as_mut() {
+        Some(frames) => frames,
+        None => return false,
+    };
+
+    let mut mapper = unsafe { OffsetPageTable::new(active_level_4_table(offset), offset) };
+    unsafe { populate(&mut mapper, frames, &region, page, offset) }.is_ok()
+}
+
+/// Maps `page` of `region` to a fresh frame filled from the region's backing.
+///
+/// ## Safety
+///
+/// The complete physical memory must be mapped at `physical_memory_offset`,
+/// and `mapper` must manage an address space in which `region` is declared.
+pub unsafe fn populate(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frames: &mut BuddyFrameAllocator,
+    region: &LazyRegion,
+    page: Page,
+    physical_memory_offset: VirtAddr,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame: PhysFrame = frames.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+    if let Backing::File(data) = region.backing {
+        let dst: *mut u8 = (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr();
+        let from = (page.start_address() - region.start) as usize;
+        if from < data.len() {
+            let len = (data.len() - from).min(4096);
+            core::ptr::copy_nonoverlapping(data[from..].as_ptr(), dst, len);
+        }
+    }
+
+    let table_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (region.flags & PageTableFlags::USER_ACCESSIBLE);
+    match mapper.map_to_with_table_flags(page, frame, region.flags, table_flags, frames) {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(error) => {
+            frames.deallocate_frame(frame);
+            Err(error)
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Page protection for the kernel's own image and the bootloader's mappings.
+//!
+//! The kernel reads its ELF program headers through `__ehdr_start`, which
+//! the linker defines at the start of the loaded image, and remaps every
+//! loadable segment with exactly the access it asks for: code read-only
+//! and executable, read-only data no-execute, and writable data (which
+//! includes .bss) writable but no-execute.
+//!
+//! The bootloader maps the physical memory mapping, the boot information
+//! and the boot stack writable and executable, in higher-half level 4
+//! slots of their own. None of them holds code, so those slots are made
+//! no-execute as a whole, which covers their huge pages too.
+
+use x86_64::VirtAddr;
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::{
+    mapper::FlagUpdateError, Mapper, Page, PageTableFlags,
+};
+use super::OffsetPageTable;
+
+extern "C" {
+    /// The ELF header of the kernel, placed at the start of its first segment.
+    static __ehdr_start: u8;
+}
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// The parts of an ELF64 program header this module needs.
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl Segment {
+    /// The page table flags matching the segment's permissions.
+    pub fn flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.executable {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let first = Page::containing_address(self.start);
+        let last = Page::containing_address(self.start + (self.size - 1));
+        Page::range_inclusive(first, last)
+    }
+}
+
+/// The address of the kernel's ELF header.
+pub fn elf_header() -> VirtAddr {
+    VirtAddr::from_ptr(unsafe { &__ehdr_start })
+}
+
+/// Returns the loadable segments of the running kernel.
+pub fn segments() -> impl Iterator<Item = Segment> {
+    unsafe {
+        let ehdr: *const u8 = elf_header().as_ptr();
+        let phoff = (ehdr.add(0x20) as *const u64).read_unaligned() as usize;
+        let phentsize = (ehdr.add(0x36) as *const u16).read_unaligned() as usize;
+        let phnum = (ehdr.add(0x38) as *const u16).read_unaligned() as usize;
+
+        (0..phnum).filter_map(move |i| {
+            let phdr = ehdr.add(phoff + i * phentsize);
+            let p_type = (phdr as *const u32).read_unaligned();
+            let p_flags = (phdr.add(4) as *const u32).read_unaligned();
+            let p_vaddr = (phdr.add(16) as *const u64).read_unaligned();
+            let p_memsz = (phdr.add(40) as *const u64).read_unaligned();
+            if p_type != PT_LOAD || p_memsz == 0 {
+                return None;
+            }
+            Some(Segment {
+                start: VirtAddr::new(p_vaddr),
+                size: p_memsz,
+                writable: p_flags & PF_W != 0,
+                executable: p_flags & PF_X != 0,
+            })
+        })
+    }
+}
+
+/// Remaps every page of the kernel image with its segment's permissions.
+/// A page shared by two segments gets the more permissive of the two.
+/// Then makes every other higher-half level 4 slot no-execute.
+///
+/// `memory::init` must have enabled no-execute support first, and nothing
+/// but the bootloader may have mapped anything in the higher half yet.
+pub fn protect(mapper: &mut OffsetPageTable) -> Result<(), FlagUpdateError> {
+    let mut previous: Option<(Page, PageTableFlags)> = None;
+    for segment in segments() {
+        for page in segment.pages() {
+            let mut flags = segment.flags();
+            if let Some((last, last_flags)) = previous {
+                if last == page {
+                    flags = (flags | (last_flags & PageTableFlags::WRITABLE))
+                        & (last_flags | !PageTableFlags::NO_EXECUTE);
+                }
+            }
+            unsafe { mapper.update_flags(page, flags)?.flush() };
+            previous = Some((page, flags));
+        }
+    }
+
+    let table = mapper.level_4_table();
+    for index in 256..512 {
+        let holds_code = segments().any(|segment| {
+            segment.executable && segment.pages().any(|page| usize::from(page.p4_index()) == index)
+        });
+        let entry = &mut table[index];
+        if !entry.is_unused() && !holds_code {
+            entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+        }
+    }
+    tlb::flush_all();
+    Ok(())
+}
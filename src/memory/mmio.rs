@@ -0,0 +1,231 @@
+//! Mapping device memory into kernel space.
+//!
+//! `map_mmio` maps a physical range into a region reserved from the VMM
+//! with the requested caching behaviour and returns an `Mmio` handle
+//! which unmaps the range again when dropped. `try_map_mmio` does the
+//! same without blocking, for paths which may run after a panic.
+//!
+//! Caching is selected through the page attribute table (PAT). `init_pat`
+//! reprograms its fifth entry to write-combining, which the stock table
+//! lacks; the other entries keep their power-on values so that existing
+//! mappings are unaffected.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{
+    mapper::UnmapError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use super::vmm::{self, VirtualMemoryManager, VmmError};
+
+/// The IA32_PAT model specific register.
+const IA32_PAT: u32 = 0x277;
+
+/// The PAT bit of a 4 KiB page table entry. It occupies the position
+/// of the huge page bit in higher level entries.
+const PAT_BIT: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+/// Memory types as encoded in the PAT.
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WB: u64 = 0x06;
+const PAT_UC_MINUS: u64 = 0x07;
+
+/// The PAT programmed by `init_pat`: the power-on defaults, except that
+/// entry 4 (selected by the PAT bit alone) is write-combining.
+const PAT_VALUE: u64 = PAT_WB
+    | PAT_WT << 8
+    | PAT_UC_MINUS << 16
+    | PAT_UC << 24
+    | PAT_WC << 32
+    | PAT_WT << 40
+    | PAT_UC_MINUS << 48
+    | PAT_UC << 56;
+
+/// Set once the PAT has been programmed with a write-combining entry.
+static WRITE_COMBINING: AtomicBool = AtomicBool::new(false);
+
+/// How the CPU may cache accesses to a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Normal cached memory.
+    WriteBack,
+    /// Reads are cached, writes go straight to the device.
+    WriteThrough,
+    /// Nothing is cached. Right for device registers.
+    Uncached,
+    /// Writes are buffered and combined, reads are not cached.
+    /// Right for framebuffers. Falls back to `Uncached` without PAT support.
+    WriteCombining,
+}
+
+impl CacheMode {
+    /// The page table flags selecting this mode's PAT entry.
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining if WRITE_COMBINING.load(Ordering::Relaxed) => PAT_BIT,
+            CacheMode::WriteCombining => CacheMode::Uncached.flags(),
+        }
+    }
+}
+
+/// Programs the PAT so that every `CacheMode` is available.
+/// Does nothing on CPUs without a PAT.
+pub fn init_pat() {
+    // CPUID.01h:EDX[16] reports PAT support.
+    let features = core::arch::x86_64::__cpuid(1);
+    if features.edx & (1 << 16) == 0 {
+        return;
+    }
+
+    unsafe {
+        Msr::new(IA32_PAT).write(PAT_VALUE);
+        // Nothing may still be cached under the old memory types.
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+    }
+    x86_64::instructions::tlb::flush_all();
+    WRITE_COMBINING.store(true, Ordering::Relaxed);
+}
+
+/// A mapped MMIO range, unmapped again on drop.
+#[derive(Debug)]
+pub struct Mmio {
+    /// The start of the VMM region, which is page aligned.
+    region: VirtAddr,
+    /// The virtual address of the requested physical address.
+    virt: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+    pages: u64,
+}
+
+impl Mmio {
+    /// The virtual address at which the physical range starts.
+    pub fn addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    /// The physical address the range was mapped from.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// The length of the range in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns a pointer to `offset` bytes into the range.
+    pub fn as_ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(offset + core::mem::size_of::<T>() <= self.len, "MMIO access out of range");
+        (self.virt + offset).as_mut_ptr()
+    }
+
+    /// Reads a value at `offset` bytes into the range.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { core::ptr::read_volatile(self.as_ptr(offset)) }
+    }
+
+    /// Writes a value at `offset` bytes into the range.
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { core::ptr::write_volatile(self.as_ptr(offset), value) }
+    }
+}
+
+impl Drop for Mmio {
+    fn drop(&mut self) {
+        let first = Page::<Size4KiB>::containing_address(self.region);
+        super::with_kernel_memory(|mapper, _| unmap_pages(first, self.pages, mapper));
+        vmm::vmm().release(self.region).expect("MMIO region vanished");
+    }
+}
+
+/// Maps `len` bytes of device memory at `phys` into kernel space.
+/// Requires `memory::install`.
+///
+/// ## Safety
+///
+/// The range must be device memory (or otherwise not in use by the frame
+/// allocator), and must not be mapped with a conflicting cache mode elsewhere.
+pub unsafe fn map_mmio(phys: PhysAddr, len: usize, mode: CacheMode) -> Result<Mmio, VmmError> {
+    let mut vmm = vmm::vmm();
+    super::with_kernel_memory(|mapper, frames| map_with(&mut vmm, mapper, frames, phys, len, mode))
+}
+
+/// Like `map_mmio`, but returns None instead of blocking if the VMM, the
+/// mapper or the frame allocator is locked, as it may be by the code a
+/// panic or fault interrupted. Dropping the handle takes the locks again.
+///
+/// ## Safety
+///
+/// As for `map_mmio`.
+pub unsafe fn try_map_mmio(phys: PhysAddr, len: usize, mode: CacheMode) -> Option<Result<Mmio, VmmError>> {
+    let mut vmm = vmm::try_vmm()?;
+    super::try_with_kernel_memory(|mapper, frames| map_with(&mut vmm, mapper, frames, phys, len, mode))
+}
+
+unsafe fn map_with(
+    vmm: &mut VirtualMemoryManager,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frames: &mut impl FrameAllocator<Size4KiB>,
+    phys: PhysAddr,
+    len: usize,
+    mode: CacheMode,
+) -> Result<Mmio, VmmError> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let offset = phys - first_frame.start_address();
+    let pages = (offset + len as u64 + 4095) / 4096;
+
+    let region = vmm.reserve(pages * 4096, 4096, "mmio")?;
+    let first = Page::<Size4KiB>::containing_address(region);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | mode.flags();
+
+    for i in 0..pages {
+        // The mapper refuses the PAT bit since it doubles as the huge
+        // page bit, so map without it and set it afterwards.
+        let mapped = mapper.map_to(first + i, first_frame + i, flags - PAT_BIT, frames);
+        match mapped {
+            Ok(flush) => flush.ignore(),
+            Err(error) => {
+                unmap_pages(first, pages, mapper);
+                vmm.release(region)?;
+                return Err(VmmError::Map(error));
+            }
+        }
+        mapper.update_flags(first + i, flags).expect("page mapped just now").flush();
+    }
+
+    Ok(Mmio {
+        region,
+        virt: region + offset,
+        phys,
+        len,
+        pages,
+    })
+}
+
+/// Unmaps device pages without handing their frames to the frame allocator.
+fn unmap_pages(first: Page, pages: u64, mapper: &mut impl Mapper<Size4KiB>) {
+    for page in Page::range(first, first + pages) {
+        // The PAT bit reads as a huge page to the mapper, so clear it first.
+        if let Ok(flush) = unsafe { mapper.update_flags(page, PageTableFlags::PRESENT) } {
+            flush.ignore();
+        }
+        match mapper.unmap(page) {
+            Ok((_, flush)) => flush.flush(),
+            Err(UnmapError::PageNotMapped) => {}
+            Err(error) => panic!("failed to unmap {:?}: {:?}", page, error),
+        }
+    }
+}
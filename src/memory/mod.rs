@@ -7,59 +7,149 @@ use x86_64::{
 };
 use x86_64::structures::paging::{
     PageTable,
+    PageTableFlags,
     FrameAllocator,
     Size4KiB,
     PhysFrame,
 };
+use bootloader::BootInfo;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
-use offset_page_table::OffsetPageTable;
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 
+pub mod address_space;
 pub mod allocator;
+pub mod bitmap;
+pub mod buddy;
+pub mod cow;
+pub mod demand;
+pub mod kernel_image;
+pub mod mmio;
+pub mod scrub;
+pub mod shm;
+pub mod stack;
+pub mod vmm;
+pub mod walker;
 mod offset_page_table;
 
+pub use offset_page_table::OffsetPageTable;
+pub use address_space::AddressSpace;
+pub use bitmap::BitmapFrameAllocator;
+pub use buddy::{BuddyFrameAllocator, FreeError};
+pub use mmio::{map_mmio, try_map_mmio, CacheMode, Mmio};
+pub use shm::SharedMemory;
+pub use walker::{MappedPageSize, Mapping, PageTableWalker, Translation};
+
+/// Marks a mapping of a frame the frame allocator does not own, such as
+/// part of the kernel image. Such frames are left alone when unmapped.
+pub const BORROWED: PageTableFlags = PageTableFlags::BIT_10;
+
+/// The virtual offset at which the complete physical memory is mapped.
+/// Recorded by `init` for code which has no other way to learn it,
+/// such as interrupt handlers.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// The physical address of the kernel's own level 4 table, recorded by `init`.
+static KERNEL_LEVEL_4_FRAME: AtomicU64 = AtomicU64::new(0);
+
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_LEVEL_4_FRAME.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+    mmio::init_pat();
+
+    // Make read-only pages read-only for the kernel too, so that its writes
+    // to copy-on-write pages and its own code fault, and honour NO_EXECUTE.
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
-pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr)
-    -> Option<PhysAddr>
-{
-    translate_addr_inner(addr, physical_memory_offset)
+/// The boot information handed over by the bootloader, recorded by `kmain`.
+static BOOT_INFO: OnceCell<&'static BootInfo> = OnceCell::uninit();
+
+/// Records the boot information so that code such as the shell can reach it.
+pub fn set_boot_info(boot_info: &'static BootInfo) {
+    BOOT_INFO.try_init_once(|| boot_info)
+        .expect("boot info recorded twice");
 }
 
-fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
-    -> Option<PhysAddr>
-{
-    use x86_64::structures::paging::page_table::FrameError;
-    use x86_64::registers::control::Cr3;
+/// Returns the boot information recorded by `set_boot_info`.
+pub fn boot_info() -> Option<&'static BootInfo> {
+    BOOT_INFO.get().copied()
+}
 
-    // read the active level 4 frame from the CR3 register
-    let (level_4_table_frame, _) = Cr3::read();
+/// The kernel's page table mapper, installed by `install` once boot-time
+/// mapping is done. Always lock this before `FRAME_ALLOCATOR`.
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/// The kernel's physical frame allocator, installed by `install`.
+pub static FRAME_ALLOCATOR: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+/// Hands the kernel mapper and frame allocator over to the global statics,
+/// so that code running after boot (such as the heap) can map memory.
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BuddyFrameAllocator) {
+    *MAPPER.lock() = Some(mapper);
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
 
-    let table_indexes = [
-        addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
-    ];
-    let mut frame = level_4_table_frame;
-
-    // traverse the multi-level page table
-    for &index in &table_indexes {
-        // convert the frame into a page table reference
-        let virt = physical_memory_offset + frame.start_address().as_u64();
-        let table_ptr: *const PageTable = virt.as_ptr();
-        let table = unsafe {&*table_ptr};
-
-        // read the page table entry and update `frame`
-        let entry = &table[index];
-        frame = match entry.frame() {
-            Ok(frame) => frame,
-            Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
-        };
+/// Runs `f` with the installed mapper and frame allocator.
+///
+/// Panics if they have not been installed yet.
+pub fn with_kernel_memory<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BuddyFrameAllocator) -> R,
+) -> R {
+    let mut mapper = MAPPER.lock();
+    let mut frames = FRAME_ALLOCATOR.lock();
+    match (mapper.as_mut(), frames.as_mut()) {
+        (Some(mapper), Some(frames)) => f(mapper, frames),
+        _ => panic!("kernel memory used before memory::install"),
     }
+}
 
-    // calculate the physical address by adding the page offset
-    Some(frame.start_address() + u64::from(addr.page_offset()))
+/// Like `with_kernel_memory`, but returns None instead of blocking or
+/// panicking if the mapper or frame allocator is unavailable. Paths which
+/// may be reached while the caller already holds the locks, such as the
+/// heap growing itself, must use this.
+pub fn try_with_kernel_memory<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BuddyFrameAllocator) -> R,
+) -> Option<R> {
+    let mut mapper = MAPPER.try_lock()?;
+    let mut frames = FRAME_ALLOCATOR.try_lock()?;
+    Some(f(mapper.as_mut()?, frames.as_mut()?))
+}
+
+/// Returns the offset recorded by `init`, or None before paging is initialized.
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    match PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) {
+        0 => None,
+        offset => Some(VirtAddr::new(offset)),
+    }
+}
+
+/// Returns the frame of the kernel's level 4 table, or None before `init`.
+pub fn kernel_level_4_frame() -> Option<PhysFrame> {
+    match KERNEL_LEVEL_4_FRAME.load(Ordering::Relaxed) {
+        0 => None,
+        addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+    }
+}
+
+/// Translates a virtual address through the active page tables,
+/// returning the physical address along with the page size and flags.
+///
+/// ## Safety
+///
+/// The complete physical memory must be mapped at `physical_memory_offset`.
+pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr)
+    -> Option<Translation>
+{
+    PageTableWalker::active(physical_memory_offset).translate(addr)
 }
 
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
@@ -76,16 +166,42 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     &mut *page_table_ptr // unsafe
 }
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
+/// A snapshot of a physical frame allocator's usage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Number of usable frames managed by the allocator.
+    pub total_frames: usize,
+    /// Number of frames available for allocation.
+    pub free_frames: usize,
+}
+
+impl FrameStats {
+    /// Number of frames currently handed out.
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+}
+
+/// A FrameAllocator that returns usable, zeroed frames from the bootloader's memory map.
+///
+/// It only ever moves forward and cannot free frames; prefer
+/// `BitmapFrameAllocator` for anything beyond early boot.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
+    physical_memory_offset: VirtAddr,
     next: usize,
 }
 
 impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+    /// ## Safety
+    ///
+    /// The caller must guarantee that all `Usable` frames in the memory map
+    /// are really unused, and that the complete physical memory is mapped
+    /// at `physical_memory_offset`.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
         BootInfoFrameAllocator {
             memory_map,
+            physical_memory_offset,
             next: 0,
         }
     }
@@ -108,6 +224,9 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
+        if let Some(frame) = frame {
+            unsafe { scrub::zero(frame.start_address().as_u64(), 1, self.physical_memory_offset) };
+        }
         frame
     }
 }
\ No newline at end of file
@@ -0,0 +1,67 @@
+//! Clearing frames between owners.
+//!
+//! The frame allocators zero every frame they hand out, so no data ever
+//! passes from one owner of a frame to the next. With the `frame-poison`
+//! feature they also fill every frame they take back with `POISON` and
+//! check, before zeroing it again, that the pattern is intact; a frame
+//! written to while free was still in use by someone who had freed it.
+//!
+//! The first bytes of each free frame may hold the allocator's own
+//! free list links, so the check skips them.
+
+use x86_64::VirtAddr;
+
+const FRAME_SIZE: u64 = 4096;
+
+/// The pattern freed frames are filled with under `frame-poison`.
+pub const POISON: u64 = 0xDEAD_F4EE_DEAD_F4EE;
+
+/// The bytes at the start of a free frame the allocators may use.
+const LINK_BYTES: usize = 16;
+
+/// Zeroes the frames at physical `addr`.
+///
+/// ## Safety
+///
+/// The frames must be unused and the complete physical memory
+/// must be mapped at `physical_memory_offset`.
+pub unsafe fn zero(addr: u64, frames: u64, physical_memory_offset: VirtAddr) {
+    let ptr: *mut u8 = (physical_memory_offset + addr).as_mut_ptr();
+    core::ptr::write_bytes(ptr, 0, (frames * FRAME_SIZE) as usize);
+}
+
+/// Fills the frames at physical `addr` with `POISON`.
+/// Does nothing without the `frame-poison` feature.
+///
+/// ## Safety
+///
+/// As for `zero`.
+pub unsafe fn poison(addr: u64, frames: u64, physical_memory_offset: VirtAddr) {
+    if cfg!(feature = "frame-poison") {
+        let words = (frames * FRAME_SIZE / 8) as usize;
+        let ptr: *mut u64 = (physical_memory_offset + addr).as_mut_ptr();
+        for i in 0..words {
+            ptr.add(i).write_volatile(POISON);
+        }
+    }
+}
+
+/// Panics if the frames at physical `addr` were written to since they were
+/// poisoned. Does nothing without the `frame-poison` feature.
+///
+/// ## Safety
+///
+/// As for `zero`.
+pub unsafe fn check_poison(addr: u64, frames: u64, physical_memory_offset: VirtAddr) {
+    if cfg!(feature = "frame-poison") {
+        for frame in 0..frames {
+            let start = addr + frame * FRAME_SIZE;
+            let ptr: *const u64 = (physical_memory_offset + start).as_ptr();
+            for i in LINK_BYTES / 8..FRAME_SIZE as usize / 8 {
+                let word = ptr.add(i).read_volatile();
+                assert!(word == POISON, "frame {:#x} was written to after being freed: \
+                    {:#x} at offset {:#x}", start, word, i * 8);
+            }
+        }
+    }
+}
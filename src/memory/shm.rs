@@ -0,0 +1,133 @@
+//! Shared memory objects.
+//!
+//! A `SharedMemory` is a fixed set of frames which can be mapped into
+//! the kernel and into any number of address spaces at once. The object
+//! and every mapping of it each own one share of every frame in the
+//! frame allocator, so the frames are freed once the last handle is
+//! dropped and the last mapping is gone, in whatever order that happens.
+//!
+//! User mappings carry the `SHARED` software bit, which keeps `fork`
+//! from turning them copy-on-write: a forked child shares them too.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, FrameDeallocator, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use super::cow::{self, CowError};
+use super::vmm::{self, VmmError};
+use super::with_kernel_memory;
+
+/// Marks a mapping of a shared memory object. One of the bits left to the OS.
+pub const SHARED: PageTableFlags = PageTableFlags::BIT_11;
+
+/// A handle to a shared memory object. Clones refer to the same frames.
+#[derive(Debug, Clone)]
+pub struct SharedMemory {
+    object: Arc<Object>,
+}
+
+#[derive(Debug)]
+struct Object {
+    frames: Vec<PhysFrame>,
+}
+
+impl SharedMemory {
+    /// Creates an object of `pages` zeroed frames. Requires `memory::install`.
+    pub fn new(pages: usize) -> Result<Self, MapToError<Size4KiB>> {
+        assert!(pages > 0, "shared memory objects cannot be empty");
+        let mut frames = Vec::with_capacity(pages);
+        let allocated = with_kernel_memory(|_, allocator| {
+            for _ in 0..pages {
+                match allocator.allocate_frame() {
+                    Some(frame) => frames.push(frame),
+                    None => return false,
+                }
+            }
+            true
+        });
+
+        // Dropping the object hands back whatever was allocated.
+        let object = Object { frames };
+        if !allocated {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+        Ok(SharedMemory { object: Arc::new(object) })
+    }
+
+    /// The frames backing the object, in order.
+    pub fn frames(&self) -> &[PhysFrame] {
+        &self.object.frames
+    }
+
+    /// The number of pages in the object.
+    pub fn pages(&self) -> usize {
+        self.object.frames.len()
+    }
+
+    /// The size of the object in bytes.
+    pub fn size(&self) -> u64 {
+        self.pages() as u64 * 4096
+    }
+
+    /// Maps the object into kernel space with `flags`. The mapping
+    /// keeps the frames alive until it is dropped.
+    pub fn map_kernel(&self, flags: PageTableFlags, name: &'static str) -> Result<KernelMapping, VmmError> {
+        let start = vmm::reserve(self.size(), name)?;
+        let first = Page::containing_address(start);
+        let flags = flags | PageTableFlags::PRESENT | SHARED;
+        let mapped = with_kernel_memory(|mapper, frames| {
+            for (i, &frame) in self.object.frames.iter().enumerate() {
+                unsafe { cow::map_shared(mapper, frames, first + i as u64, frame, flags)? };
+            }
+            Ok(())
+        });
+
+        let mapping = KernelMapping { start };
+        match mapped {
+            Ok(()) => Ok(mapping),
+            Err(CowError::Map(error)) => Err(VmmError::Map(error)),
+            Err(error) => panic!("failed to map shared memory: {:?}", error),
+        }
+    }
+
+    /// Returns true if `other` refers to the same object.
+    pub fn same_object(&self, other: &SharedMemory) -> bool {
+        Arc::ptr_eq(&self.object, &other.object)
+    }
+}
+
+impl Drop for Object {
+    fn drop(&mut self) {
+        with_kernel_memory(|_, allocator| {
+            for &frame in self.frames.iter() {
+                unsafe { allocator.deallocate_frame(frame) };
+            }
+        });
+    }
+}
+
+/// A mapping of a shared memory object into kernel space,
+/// unmapped again on drop.
+#[derive(Debug)]
+pub struct KernelMapping {
+    start: VirtAddr,
+}
+
+impl KernelMapping {
+    /// The start of the mapping.
+    pub fn addr(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.start.as_mut_ptr()
+    }
+}
+
+impl Drop for KernelMapping {
+    fn drop(&mut self) {
+        vmm::free(self.start).expect("shared memory region vanished");
+    }
+}
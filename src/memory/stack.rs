@@ -0,0 +1,124 @@
+//! Kernel stacks with unmapped guard pages.
+//!
+//! Every stack is mapped in its own region reserved from the VMM,
+//! with an unmapped page directly below it. Overflowing a stack then
+//! faults on the guard page instead of silently running into whatever
+//! lies below, and the fault handlers can name the stack that overflowed.
+
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+};
+use super::vmm::{self, VmmError};
+
+/// Where the bootloader places the boot stack. This must match
+/// `kernel-stack-address` in the bootloader section of `Cargo.toml`.
+pub const BOOT_STACK_ADDRESS: u64 = 0x_FFFF_FF80_0000_0000;
+
+/// The size of the boot stack in pages. This must match
+/// `kernel-stack-size` in the bootloader section of `Cargo.toml`.
+pub const BOOT_STACK_PAGES: u64 = 512;
+
+/// The maximum number of stacks that can be registered at once.
+const MAX_STACKS: usize = 64;
+
+/// A kernel stack and the guard page below it.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelStack {
+    pub name: &'static str,
+    /// The unmapped page directly below the stack.
+    pub guard: Page,
+    /// One past the highest usable address; the initial stack pointer.
+    pub top: VirtAddr,
+}
+
+impl KernelStack {
+    /// The lowest usable address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        self.guard.start_address() + self.guard.size()
+    }
+
+    /// Returns true if `addr` lies inside this stack's guard page.
+    pub fn guard_contains(&self, addr: VirtAddr) -> bool {
+        self.guard.start_address() <= addr && addr < self.bottom()
+    }
+}
+
+/// All known kernel stacks, so that the fault handlers can look
+/// them up without allocating.
+static STACKS: Mutex<[Option<KernelStack>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+/// Allocates and maps a stack of `pages` pages with a guard page below it.
+pub fn allocate(name: &'static str, pages: u64) -> Result<KernelStack, VmmError> {
+    let slot = (pages + 1) * 4096;
+    let start = vmm::reserve(slot, name)?;
+
+    let guard = Page::containing_address(start);
+    let mapped = crate::memory::with_kernel_memory(|mapper, frames| {
+        map_stack(guard + 1, pages, mapper, frames)
+    });
+    if let Err(error) = mapped {
+        // Hands back whatever was mapped before the failure.
+        vmm::free(start)?;
+        return Err(error.into());
+    }
+
+    let stack = KernelStack {
+        name,
+        guard,
+        top: start + slot,
+    };
+    register(stack);
+    Ok(stack)
+}
+
+/// Records the boot stack set up by the bootloader,
+/// which leaves the lowest page of it unmapped as a guard page.
+pub fn register_boot_stack() {
+    let guard = Page::containing_address(VirtAddr::new(BOOT_STACK_ADDRESS));
+    register(KernelStack {
+        name: "boot stack",
+        guard,
+        top: (guard + 1 + BOOT_STACK_PAGES).start_address(),
+    });
+}
+
+/// Records a stack so that overflows into its guard page can be reported.
+pub fn register(stack: KernelStack) {
+    let mut stacks = STACKS.lock();
+    let slot = stacks.iter_mut()
+        .find(|s| s.is_none())
+        .expect("too many kernel stacks registered");
+    *slot = Some(stack);
+}
+
+/// Returns the stack whose guard page contains `addr`, if any.
+///
+/// Called from fault handlers, so this never blocks: if the stack
+/// table is locked by the interrupted code, no stack is reported.
+pub fn guard_page_owner(addr: VirtAddr) -> Option<KernelStack> {
+    let stacks = STACKS.try_lock()?;
+    stacks.iter()
+        .flatten()
+        .find(|s| s.guard_contains(addr))
+        .copied()
+}
+
+fn map_stack(
+    first: Page,
+    pages: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    for page in Page::range(first, first + pages) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush()
+        };
+    }
+    Ok(())
+}
@@ -0,0 +1,312 @@
+//! The kernel virtual memory manager.
+//!
+//! Hands out page-aligned ranges of the higher half so that the heap,
+//! kernel stacks and device windows never collide. Regions can either be
+//! reserved, leaving the owner to map them, or allocated, in which case
+//! fresh frames are mapped with the requested flags and freed again later.
+//!
+//! The region table is a fixed-size array, so the manager never touches
+//! the heap and can be used before it exists and from fault handlers.
+
+use spin::{Mutex, MutexGuard};
+use x86_64::VirtAddr;
+use super::demand::{self, Backing, DemandError};
+use x86_64::structures::paging::{
+    mapper::{MapToError, TranslateResult, UnmapError},
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTable, PageTableFlags, Size4KiB, Translate,
+};
+
+/// Start of the kernel half of the address space.
+pub const KERNEL_SPACE_START: u64 = 0x_FFFF_8000_0000_0000;
+
+/// End of the kernel half. The very last page is never handed out,
+/// so that region ends always fit in a u64.
+pub const KERNEL_SPACE_END: u64 = 0x_FFFF_FFFF_FFFF_F000;
+
+/// The maximum number of regions that can exist at once.
+const MAX_REGIONS: usize = 128;
+
+/// The span of virtual memory covered by one level 4 entry.
+const P4_ENTRY_SIZE: u64 = 512 * 512 * 512 * 4096;
+
+#[derive(Debug)]
+pub enum VmmError {
+    /// No free range of the requested size is left.
+    OutOfVirtualSpace,
+    /// The requested range overlaps an existing region.
+    Overlap,
+    /// No region starts at the given address.
+    NotFound,
+    /// The region table is full.
+    TooManyRegions,
+    /// Mapping the region failed.
+    Map(MapToError<Size4KiB>),
+    /// Declaring the region as lazily backed failed.
+    Demand(DemandError),
+}
+
+impl From<MapToError<Size4KiB>> for VmmError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        VmmError::Map(error)
+    }
+}
+
+/// A range of kernel virtual memory owned by someone.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub name: &'static str,
+    /// The flags of the pages the manager mapped for this region,
+    /// or None if the owner maps the region itself.
+    pub flags: Option<PageTableFlags>,
+}
+
+impl Region {
+    /// One past the last address of the region.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    fn overlaps(&self, start: u64, size: u64) -> bool {
+        start < self.end().as_u64() && self.start.as_u64() < start + size
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let first = Page::containing_address(self.start);
+        Page::range(first, first + self.size / 4096)
+    }
+}
+
+/// Tracks which parts of the kernel half are in use.
+pub struct VirtualMemoryManager {
+    regions: [Option<Region>; MAX_REGIONS],
+    initialized: bool,
+}
+
+impl VirtualMemoryManager {
+    const fn new() -> Self {
+        VirtualMemoryManager {
+            regions: [None; MAX_REGIONS],
+            initialized: false,
+        }
+    }
+
+    /// Reserves every level 4 slot of the kernel half which is already
+    /// in use, such as the bootloader's physical memory mapping and
+    /// the boot stack, so that nothing is handed out on top of them.
+    fn init(&mut self, level_4_table: &PageTable) {
+        for index in 256..512 {
+            if !level_4_table[index].is_unused() {
+                let start = VirtAddr::new_truncate(index as u64 * P4_ENTRY_SIZE);
+                let size = if index == 511 {
+                    KERNEL_SPACE_END - start.as_u64()
+                } else {
+                    P4_ENTRY_SIZE
+                };
+                self.insert(Region { start, size, name: "bootloader", flags: None })
+                    .expect("region table full during init");
+            }
+        }
+        self.initialized = true;
+    }
+
+    /// Returns the region containing `addr`.
+    pub fn find(&self, addr: VirtAddr) -> Option<&Region> {
+        self.regions.iter().flatten().find(|r| r.contains(addr))
+    }
+
+    /// Returns all regions, in no particular order.
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter().flatten()
+    }
+
+    /// Reserves `size` bytes anywhere in the kernel half, aligned to `align`.
+    pub fn reserve(&mut self, size: u64, align: u64, name: &'static str) -> Result<VirtAddr, VmmError> {
+        let size = page_align(size);
+        let align = align.max(4096);
+        let mut candidate = KERNEL_SPACE_START;
+
+        // First fit: skip past each region in the way until a gap is found.
+        loop {
+            candidate = candidate.checked_add(align - 1).ok_or(VmmError::OutOfVirtualSpace)? / align * align;
+            if candidate.checked_add(size).map_or(true, |end| end > KERNEL_SPACE_END) {
+                return Err(VmmError::OutOfVirtualSpace);
+            }
+            match self.regions().find(|r| r.overlaps(candidate, size)) {
+                Some(region) => candidate = region.end().as_u64(),
+                None => break,
+            }
+        }
+
+        let start = VirtAddr::new(candidate);
+        self.insert(Region { start, size, name, flags: None })?;
+        Ok(start)
+    }
+
+    /// Reserves the range `[start, start + size)`.
+    pub fn reserve_at(&mut self, start: VirtAddr, size: u64, name: &'static str) -> Result<(), VmmError> {
+        let size = page_align(size);
+        if self.regions().any(|r| r.overlaps(start.as_u64(), size)) {
+            return Err(VmmError::Overlap);
+        }
+        self.insert(Region { start, size, name, flags: None })
+    }
+
+    /// Drops the reservation of the region starting at `start`
+    /// without touching its mappings.
+    pub fn release(&mut self, start: VirtAddr) -> Result<Region, VmmError> {
+        let slot = self.regions.iter_mut()
+            .find(|r| matches!(r, Some(r) if r.start == start))
+            .ok_or(VmmError::NotFound)?;
+        Ok(slot.take().unwrap())
+    }
+
+    /// Reserves `size` bytes and backs them with fresh frames mapped with `flags`.
+    pub fn allocate(
+        &mut self,
+        size: u64,
+        flags: PageTableFlags,
+        name: &'static str,
+        mapper: &mut (impl Mapper<Size4KiB> + Translate),
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Result<VirtAddr, VmmError> {
+        let start = self.reserve(size, 4096, name)?;
+        let region = self.set_flags(start, flags);
+
+        for page in region.pages() {
+            let mapped = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)
+                .and_then(|frame| unsafe {
+                    mapper.map_to(page, frame, flags | PageTableFlags::PRESENT, frame_allocator)
+                });
+            match mapped {
+                Ok(flush) => flush.flush(),
+                Err(error) => {
+                    self.free(start, mapper, frame_allocator)
+                        .expect("failed to roll back a partial allocation");
+                    return Err(error.into());
+                }
+            }
+        }
+
+        Ok(start)
+    }
+
+    /// Unmaps a region created by `allocate` or `reserve_lazy`, returns its frames and
+    /// drops the reservation. Pages which were never mapped are skipped,
+    /// and frames of `BORROWED` pages are not returned.
+    pub fn free(
+        &mut self,
+        start: VirtAddr,
+        mapper: &mut (impl Mapper<Size4KiB> + Translate),
+        frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) -> Result<(), VmmError> {
+        let region = self.release(start)?;
+        for page in region.pages() {
+            let borrowed = matches!(
+                mapper.translate(page.start_address()),
+                TranslateResult::Mapped { flags, .. } if flags.contains(super::BORROWED)
+            );
+            match mapper.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.flush();
+                    if !borrowed {
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                    }
+                }
+                Err(UnmapError::PageNotMapped) => {}
+                Err(error) => panic!("failed to unmap {:?}: {:?}", page, error),
+            }
+        }
+        Ok(())
+    }
+
+    fn set_flags(&mut self, start: VirtAddr, flags: PageTableFlags) -> Region {
+        let region = self.regions.iter_mut()
+            .flatten()
+            .find(|r| r.start == start)
+            .expect("region vanished");
+        region.flags = Some(flags);
+        *region
+    }
+
+    fn insert(&mut self, region: Region) -> Result<(), VmmError> {
+        let slot = self.regions.iter_mut()
+            .find(|r| r.is_none())
+            .ok_or(VmmError::TooManyRegions)?;
+        *slot = Some(region);
+        Ok(())
+    }
+}
+
+fn page_align(size: u64) -> u64 {
+    (size + 4095) / 4096 * 4096
+}
+
+static VMM: Mutex<VirtualMemoryManager> = Mutex::new(VirtualMemoryManager::new());
+
+/// Locks the global manager, setting it up from the active
+/// page tables on first use. Requires `memory::init`.
+pub fn vmm() -> MutexGuard<'static, VirtualMemoryManager> {
+    let mut vmm = VMM.lock();
+    if !vmm.initialized {
+        let offset = super::physical_memory_offset()
+            .expect("the VMM requires memory::init");
+        vmm.init(unsafe { super::active_level_4_table(offset) });
+    }
+    vmm
+}
+
+/// Like `vmm`, but returns None instead of blocking if the manager is locked.
+pub fn try_vmm() -> Option<MutexGuard<'static, VirtualMemoryManager>> {
+    let mut vmm = VMM.try_lock()?;
+    if !vmm.initialized {
+        let offset = super::physical_memory_offset()?;
+        vmm.init(unsafe { super::active_level_4_table(offset) });
+    }
+    Some(vmm)
+}
+
+/// Reserves `size` bytes of kernel virtual memory for the caller to map.
+pub fn reserve(size: u64, name: &'static str) -> Result<VirtAddr, VmmError> {
+    vmm().reserve(size, 4096, name)
+}
+
+/// Allocates `size` bytes of kernel virtual memory backed by fresh frames.
+/// Requires `memory::install`.
+pub fn allocate(size: u64, flags: PageTableFlags, name: &'static str) -> Result<VirtAddr, VmmError> {
+    let mut vmm = vmm();
+    super::with_kernel_memory(|mapper, frames| vmm.allocate(size, flags, name, mapper, frames))
+}
+
+/// Reserves `size` bytes of kernel virtual memory whose pages are only
+/// backed, with `flags`, when first touched. Free it again with `free`.
+pub fn reserve_lazy(
+    size: u64,
+    flags: PageTableFlags,
+    backing: Backing,
+    name: &'static str,
+) -> Result<VirtAddr, VmmError> {
+    let mut vmm = vmm();
+    let start = vmm.reserve(size, 4096, name)?;
+    let region = vmm.set_flags(start, flags);
+    if let Err(error) = demand::declare_kernel(start, region.size, flags, backing) {
+        vmm.release(start)?;
+        return Err(VmmError::Demand(error));
+    }
+    Ok(start)
+}
+
+/// Frees a region created by `allocate` or `reserve_lazy`.
+/// Requires `memory::install`.
+pub fn free(start: VirtAddr) -> Result<(), VmmError> {
+    let mut vmm = vmm();
+    demand::forget(None, start);
+    super::with_kernel_memory(|mapper, frames| vmm.free(start, mapper, frames))
+}
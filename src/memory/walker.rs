@@ -0,0 +1,231 @@
+//! A read-only page table walker which understands huge pages.
+//!
+//! Used for address translation and for inspecting the active
+//! mappings, so that callers don't each reimplement the walk.
+
+use x86_64::{
+    VirtAddr,
+    PhysAddr,
+};
+use x86_64::structures::paging::{
+    PageTable,
+    PageTableFlags,
+    PhysFrame,
+};
+
+/// The size of the page backing a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappedPageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl MappedPageSize {
+    /// The size of the page in bytes.
+    pub fn bytes(self) -> u64 {
+        match self {
+            MappedPageSize::Size4KiB => 4096,
+            MappedPageSize::Size2MiB => 4096 * 512,
+            MappedPageSize::Size1GiB => 4096 * 512 * 512,
+        }
+    }
+
+    /// The page size of a leaf entry found in the table at `depth`,
+    /// where the level 4 table has depth 0.
+    fn at_depth(depth: usize) -> Self {
+        match depth {
+            1 => MappedPageSize::Size1GiB,
+            2 => MappedPageSize::Size2MiB,
+            _ => MappedPageSize::Size4KiB,
+        }
+    }
+}
+
+/// A present leaf mapping in the page tables.
+///
+/// The flags are the effective flags of the whole walk: a page is only
+/// writable or user accessible if every level allows it, and it is
+/// no-execute if any level says so.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: VirtAddr,
+    pub frame: PhysAddr,
+    pub size: MappedPageSize,
+    pub flags: PageTableFlags,
+}
+
+/// The result of translating a single virtual address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub addr: PhysAddr,
+    pub size: MappedPageSize,
+    pub flags: PageTableFlags,
+}
+
+/// Walks a page table hierarchy through the complete physical memory mapping.
+pub struct PageTableWalker {
+    level_4_frame: PhysFrame,
+    physical_memory_offset: VirtAddr,
+}
+
+impl PageTableWalker {
+    /// Creates a walker for the hierarchy rooted at `level_4_frame`.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must guarantee that the complete physical memory is mapped
+    /// at `physical_memory_offset` and that `level_4_frame` holds a valid
+    /// level 4 page table.
+    pub unsafe fn new(level_4_frame: PhysFrame, physical_memory_offset: VirtAddr) -> Self {
+        PageTableWalker {
+            level_4_frame,
+            physical_memory_offset,
+        }
+    }
+
+    /// Creates a walker for the currently active hierarchy in `CR3`.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must guarantee that the complete physical memory is mapped
+    /// at `physical_memory_offset`.
+    pub unsafe fn active(physical_memory_offset: VirtAddr) -> Self {
+        use x86_64::registers::control::Cr3;
+
+        let (level_4_frame, _) = Cr3::read();
+        Self::new(level_4_frame, physical_memory_offset)
+    }
+
+    /// Translates a virtual address, following 1 GiB and 2 MiB leaf entries.
+    pub fn translate(&self, addr: VirtAddr) -> Option<Translation> {
+        let indexes = [
+            addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
+        ];
+        let mut table = self.table(self.level_4_frame.start_address());
+        let mut flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+        for (depth, &index) in indexes.iter().enumerate() {
+            let entry = &table[index];
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            flags = combine(flags, entry.flags());
+
+            if is_leaf(depth, entry.flags()) {
+                let size = MappedPageSize::at_depth(depth);
+                let offset = addr.as_u64() & (size.bytes() - 1);
+                return Some(Translation {
+                    addr: leaf_frame(entry.addr(), size) + offset,
+                    size,
+                    flags,
+                });
+            }
+            table = self.table(entry.addr());
+        }
+
+        unreachable!("level 1 entries are always leaves")
+    }
+
+    /// Returns an iterator over every present leaf mapping, in address order.
+    pub fn mappings(&self) -> Mappings<'_> {
+        let root = self.table(self.level_4_frame.start_address());
+        Mappings {
+            walker: self,
+            tables: [root; 4],
+            indexes: [0; 4],
+            flags: [PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE; 4],
+            depth: 0,
+        }
+    }
+
+    fn table(&self, addr: PhysAddr) -> &PageTable {
+        let virt = self.physical_memory_offset + addr.as_u64();
+        unsafe { &*virt.as_ptr() }
+    }
+}
+
+/// An iterator over the present leaf mappings of a page table hierarchy.
+pub struct Mappings<'a> {
+    walker: &'a PageTableWalker,
+    tables: [&'a PageTable; 4],
+    indexes: [usize; 4],
+    /// Effective flags accumulated above each depth.
+    flags: [PageTableFlags; 4],
+    depth: usize,
+}
+
+impl<'a> Iterator for Mappings<'a> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        loop {
+            let depth = self.depth;
+            if self.indexes[depth] == 512 {
+                if depth == 0 {
+                    return None;
+                }
+                self.depth -= 1;
+                self.indexes[depth - 1] += 1;
+                continue;
+            }
+
+            let entry = &self.tables[depth][self.indexes[depth]];
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                self.indexes[depth] += 1;
+                continue;
+            }
+            let flags = combine(self.flags[depth], entry.flags());
+
+            if is_leaf(depth, entry.flags()) {
+                let size = MappedPageSize::at_depth(depth);
+                let mapping = Mapping {
+                    start: self.address(),
+                    frame: leaf_frame(entry.addr(), size),
+                    size,
+                    flags,
+                };
+                self.indexes[depth] += 1;
+                return Some(mapping);
+            }
+
+            self.tables[depth + 1] = self.walker.table(entry.addr());
+            self.indexes[depth + 1] = 0;
+            self.flags[depth + 1] = flags;
+            self.depth += 1;
+        }
+    }
+}
+
+impl<'a> Mappings<'a> {
+    /// The virtual address described by the current table indexes.
+    fn address(&self) -> VirtAddr {
+        let mut addr = 0;
+        for depth in 0..=self.depth {
+            addr |= (self.indexes[depth] as u64) << (39 - 9 * depth);
+        }
+        VirtAddr::new_truncate(addr)
+    }
+}
+
+/// Returns true if an entry at `depth` maps a page rather than a table.
+/// Bit 7 of a level 1 entry is the PAT bit, not the huge page bit.
+fn is_leaf(depth: usize, flags: PageTableFlags) -> bool {
+    depth == 3 || (depth != 0 && flags.contains(PageTableFlags::HUGE_PAGE))
+}
+
+/// The frame a leaf entry maps. Bit 12 of a huge page entry is its PAT
+/// bit, which `PageTableEntry::addr` leaves in the address.
+fn leaf_frame(addr: PhysAddr, size: MappedPageSize) -> PhysAddr {
+    addr.align_down(size.bytes())
+}
+
+/// Folds an entry's flags into the effective flags of the walk so far.
+fn combine(parent: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let restrictive = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut flags = entry & !restrictive | (entry & parent & restrictive);
+    if parent.contains(PageTableFlags::NO_EXECUTE) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
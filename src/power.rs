@@ -0,0 +1,144 @@
+//! Turning the machine off and restarting it.
+//!
+//! `shutdown` enters the ACPI S5 soft-off state: it writes the sleep
+//! type values of the DSDT's `\_S5` object, with SLP_EN, to the PM1
+//! control registers the FADT describes. If that doesn't work, it tries
+//! the ports the QEMU and Bochs power management devices listen on.
+//!
+//! `reboot` writes the FADT's reset register, then pulses the CPU reset
+//! line through the 8042 keyboard controller, and finally triple-faults,
+//! which resets every PC.
+
+use x86_64::instructions::{interrupts, port::Port, tables::lidt};
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+use crate::acpi::{self, Fadt, GenericAddress};
+use crate::{hlt_loop, println, printsln};
+
+/// The PM1 control register's bits.
+const SCI_EN: u64 = 1 << 0;
+const SLP_TYP_SHIFT: u64 = 10;
+const SLP_TYP: u64 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u64 = 1 << 13;
+
+/// The ACPI sleep state which turns the machine off.
+const S5: u8 = 5;
+
+/// The 8042 keyboard controller's status and command port.
+const KBC_COMMAND: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    /// No valid FADT was found.
+    NoFadt,
+    /// Neither the DSDT nor an SSDT declares a `\_S5` package.
+    NoSleepType,
+    /// The FADT has no PM1a control block.
+    NoControlBlock,
+    /// The control block is in an address space the kernel can't access.
+    Unsupported,
+    /// The firmware did not switch to ACPI mode.
+    AcpiModeFailed,
+    /// The sleep registers were written, but the machine is still on.
+    StillRunning,
+}
+
+/// Turns the machine off.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    let error = unsafe { acpi_shutdown() }.unwrap_err();
+    println!("ACPI shutdown failed: {:?}", error);
+    printsln!("ACPI shutdown failed: {:?}", error);
+
+    unsafe {
+        // QEMU's PIIX4 power management device, then Bochs and older QEMU.
+        Port::<u16>::new(0x604).write(0x2000);
+        Port::<u16>::new(0xB004).write(0x2000);
+    }
+    println!("The machine could not be turned off.");
+    printsln!("The machine could not be turned off.");
+    hlt_loop()
+}
+
+/// Restarts the machine.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    unsafe {
+        if let Some((register, value)) = acpi::fadt().and_then(|fadt| fadt.reset_register()) {
+            register.write(value as u64);
+            delay();
+        }
+
+        let mut command = Port::<u8>::new(KBC_COMMAND);
+        for _ in 0..100_000 {
+            if command.read() & KBC_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        command.write(KBC_PULSE_RESET);
+        delay();
+
+        // Without an IDT the breakpoint becomes a double and then a triple fault.
+        lidt(&DescriptorTablePointer { limit: 0, base: VirtAddr::zero() });
+        interrupts::int3();
+    }
+    hlt_loop()
+}
+
+/// Enters S5 through the FADT's PM1 control blocks. Only returns on failure.
+unsafe fn acpi_shutdown() -> Result<(), PowerError> {
+    let fadt = acpi::fadt().ok_or(PowerError::NoFadt)?;
+    let sleep_types = acpi::sleep_types(S5).ok_or(PowerError::NoSleepType)?;
+    let pm1a = fadt.pm1a_control_block().ok_or(PowerError::NoControlBlock)?;
+    enable_acpi(&fadt, &pm1a)?;
+
+    // Both blocks get their sleep type before either is enabled.
+    let blocks = [
+        Some((pm1a, sleep_types.a)),
+        fadt.pm1b_control_block().map(|pm1b| (pm1b, sleep_types.b)),
+    ];
+    for &(block, sleep_type) in blocks.iter().flatten() {
+        let value = block.read().ok_or(PowerError::Unsupported)? & !(SLP_TYP | SLP_EN);
+        block.write(value | (sleep_type as u64) << SLP_TYP_SHIFT);
+    }
+    for &(block, _) in blocks.iter().flatten() {
+        let value = block.read().ok_or(PowerError::Unsupported)?;
+        block.write(value | SLP_EN);
+    }
+    delay();
+    Err(PowerError::StillRunning)
+}
+
+/// Switches the firmware to ACPI mode if it isn't already, which hands
+/// the power management registers over to the kernel.
+unsafe fn enable_acpi(fadt: &Fadt, pm1a: &GenericAddress) -> Result<(), PowerError> {
+    let enabled = || pm1a.read().map_or(false, |value| value & SCI_EN != 0);
+    if enabled() || fadt.smi_command_port() == 0 || fadt.acpi_enable() == 0 {
+        return Ok(());
+    }
+    Port::<u8>::new(fadt.smi_command_port() as u16).write(fadt.acpi_enable());
+    for _ in 0..1000 {
+        if enabled() {
+            return Ok(());
+        }
+        delay_short();
+    }
+    Err(PowerError::AcpiModeFailed)
+}
+
+/// Waits about 100 ms for the hardware to act.
+fn delay() {
+    for _ in 0..100 {
+        delay_short();
+    }
+}
+
+/// Waits about a millisecond: every write to the POST code port takes a microsecond.
+fn delay_short() {
+    let mut port = Port::<u8>::new(0x80);
+    for _ in 0..1000 {
+        unsafe { port.write(0) };
+    }
+}
@@ -197,12 +197,14 @@ impl Descriptor {
         Descriptor::UserSegment(DescriptorFlags::USER_CODE64.bits())
     }
     
+    /// Creates a TSS descriptor. Only the address is kept: the CPU reads
+    /// and writes the TSS itself, so it is passed as a raw pointer.
     #[inline]
-    pub fn tss_segment(tss: &'static crate::segmentation::TaskStateSegment) -> Descriptor {
+    pub fn tss_segment(tss: *const crate::segmentation::TaskStateSegment) -> Descriptor {
         use self::DescriptorFlags as Flags;
         use core::mem::size_of;
 
-        let ptr = tss as *const _ as u64;
+        let ptr = tss as u64;
 
         let mut low = Flags::PRESENT.bits();
         // base
@@ -1,3 +1,5 @@
+use core::cell::UnsafeCell;
+use core::ptr::addr_of;
 use x86_64::{VirtAddr, instructions::segmentation::Segment};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::SegmentSelector;
@@ -9,27 +11,41 @@ use gdt::{GlobalDescriptorTable, Descriptor};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+/// The size of the double fault stack in pages.
+const DOUBLE_FAULT_STACK_PAGES: u64 = 5;
+
+/// The task state segment. Its interrupt stacks start out on a static
+/// stack in .bss so that faults during early boot can be handled, and are
+/// moved onto guarded stacks by `init_stacks` once memory is available.
+static TSS: Tss = Tss(UnsafeCell::new(TaskStateSegment::new()));
+
+/// The CPU uses the TSS behind Rust's back, so no reference to it is
+/// ever made: it is only accessed through the cell's raw pointer.
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// Only written with interrupts disabled, and there is one CPU.
+unsafe impl Sync for Tss {}
+
+/// Points interrupt stack table entry `index` at the stack ending at `top`.
+fn set_interrupt_stack(index: u16, top: VirtAddr) {
+    // The CPU reads the IST from the loaded TSS on every interrupt,
+    // so updating the entry in place is enough.
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        (*TSS.0.get()).interrupt_stack_table[index as usize] = top;
+    });
 }
 
+const EARLY_STACK_SIZE: usize = 4096 * 5;
+
+/// A static stack for the double fault handler, used until `init_stacks`.
+/// Nothing guards it from below.
+static mut EARLY_DOUBLE_FAULT_STACK: [u8; EARLY_STACK_SIZE] = [0; EARLY_STACK_SIZE];
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::empty();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(TSS.0.get()));
         (gdt, Selectors { code_selector, tss_selector })
     };
 }
@@ -43,9 +59,20 @@ pub fn init() {
     use x86_64::instructions::segmentation::CS;
     use x86_64::instructions::tables::load_tss;
 
+    let stack_start = VirtAddr::from_ptr(addr_of!(EARLY_DOUBLE_FAULT_STACK));
+    set_interrupt_stack(DOUBLE_FAULT_IST_INDEX, stack_start + EARLY_STACK_SIZE);
+
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Moves the interrupt stacks onto guarded kernel stacks.
+/// Must be called after `memory::install`.
+pub fn init_stacks() {
+    let stack = crate::memory::stack::allocate("double fault stack", DOUBLE_FAULT_STACK_PAGES)
+        .expect("failed to allocate the double fault stack");
+    set_interrupt_stack(DOUBLE_FAULT_IST_INDEX, stack.top);
+}
@@ -1,4 +1,6 @@
-use super::{Task, TaskId};
+use super::{oom, Task, TaskId};
+use crate::memory::allocator;
+use crate::{println, printsln};
 use alloc::{collections::BTreeMap, sync::Arc};
 use alloc::task::Wake;
 use core::task::{Waker, Context, Poll};
@@ -49,6 +51,8 @@ impl Executor {
     }
 
     /// Pop all tasks off the queue, run them, and push all incomplete tasks.
+    /// A task which exhausts the heap or its budget is dropped once its
+    /// poll returns, and the others keep running.
     fn run_ready_tasks(&mut self) {
         // destructure `self` to avoid borrow checker errors
         let Self {
@@ -66,14 +70,28 @@ impl Executor {
                 .entry(task_id)
                 .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
             let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
+            allocator::take_charge();
+            let result = oom::with_reserve(|| task.poll(&mut context));
+            task.allocated += allocator::take_charge();
+            let reason = match result {
+                Ok(Poll::Ready(())) => {
                     // task done -> remove it and its cached waker
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    continue;
                 }
-                Poll::Pending => {}
-            }
+                Ok(Poll::Pending) if task.over_budget() => "over budget",
+                Ok(Poll::Pending) => continue,
+                Err(_) => "out of memory",
+            };
+
+            // Dropping the task runs its destructors and frees its memory.
+            let allocated = task.allocated();
+            tasks.remove(&task_id);
+            waker_cache.remove(&task_id);
+            oom::record_kill();
+            println!("Killed task {}: {} ({} bytes allocated)", task_id.0, reason, allocated);
+            printsln!("Killed task {}: {} ({} bytes allocated)", task_id.0, reason, allocated);
         }
     }
 }
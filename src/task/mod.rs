@@ -5,6 +5,7 @@ use core::sync::atomic::{AtomicU64, Ordering};
 
 pub mod executor;
 pub mod keyboard;
+pub mod oom;
 pub mod shell;
 
 /// A task is any cooperative multitasking job.
@@ -13,6 +14,10 @@ pub mod shell;
 pub struct Task {
     id: TaskId,
     future: Pin<Box<dyn Future<Output = ()>>>,
+    /// Heap bytes allocated minus bytes freed while this task was polled.
+    allocated: isize,
+    /// The most heap the task may hold at the end of a poll, if limited.
+    budget: Option<usize>,
 }
 
 impl Task {
@@ -21,9 +26,28 @@ impl Task {
         Task {
             id: TaskId::new(),
             future: Box::pin(future),
+            allocated: 0,
+            budget: None,
         }
     }
 
+    /// Create a task which is killed if it holds more than `budget` heap
+    /// bytes when it returns to the executor.
+    pub fn with_budget(future: impl Future<Output = ()> + 'static, budget: usize) -> Task {
+        Task { budget: Some(budget), ..Task::new(future) }
+    }
+
+    /// The heap bytes attributed to this task: those allocated minus
+    /// those freed while it ran.
+    pub fn allocated(&self) -> usize {
+        self.allocated.max(0) as usize
+    }
+
+    /// Returns true if the task holds more heap than its budget allows.
+    fn over_budget(&self) -> bool {
+        self.budget.map_or(false, |budget| self.allocated() > budget)
+    }
+
     /// Poll a task to see if it is ready.
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
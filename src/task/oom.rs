@@ -0,0 +1,56 @@
+//! Recovering from heap exhaustion by killing the task responsible.
+//!
+//! The executor polls each task through `with_reserve`. If the heap runs
+//! out while the task runs, the allocation is served from the allocator's
+//! emergency reserve instead of failing, so the task's poll carries on and
+//! returns normally. The executor then drops the task at that poll
+//! boundary: its destructors run and its locks are released like on any
+//! other exit, and its memory, including what it took from the reserve,
+//! goes back to the heap.
+//!
+//! Tasks can also be given a heap budget with `Task::with_budget`. The
+//! executor kills a task whose attributed usage exceeds its budget when
+//! the poll which went over returns.
+//!
+//! Allocations which fail outside a poll, or after the reserve ran out
+//! too, still reach the allocation error handler, which panics.
+
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::memory::allocator;
+
+/// The number of tasks killed for exhausting the heap or their budget.
+static KILLS: AtomicUsize = AtomicUsize::new(0);
+
+/// The heap was exhausted while running the closure passed to `with_reserve`.
+#[derive(Debug, Clone, Copy)]
+pub struct OutOfMemory {
+    /// The first allocation which could not be satisfied.
+    pub layout: Layout,
+}
+
+/// Runs `f` with the emergency reserve available, returning `OutOfMemory`
+/// instead of its result if it had to fall back on the reserve.
+/// Calls do not nest.
+pub fn with_reserve<R>(f: impl FnOnce() -> R) -> Result<R, OutOfMemory> {
+    // Drop any failure recorded outside a poll.
+    allocator::take_exhaustion();
+    allocator::enable_reserve(true);
+    let result = f();
+    allocator::enable_reserve(false);
+
+    match allocator::take_exhaustion() {
+        Some(layout) => Err(OutOfMemory { layout }),
+        None => Ok(result),
+    }
+}
+
+/// Counts a task killed by the executor.
+pub(super) fn record_kill() {
+    KILLS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns how many tasks have been killed for running out of memory.
+pub fn kills() -> usize {
+    KILLS.load(Ordering::Relaxed)
+}
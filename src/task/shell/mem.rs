@@ -0,0 +1,172 @@
+//! Memory inspection commands.
+use alloc::vec::Vec;
+use core::fmt;
+use bootloader::bootinfo::MemoryRegionType;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::PageTableFlags;
+use crate::println;
+use crate::memory::{self, MappedPageSize, Mapping, PageTableWalker};
+
+/// Displays a byte count in the largest unit that keeps it readable.
+pub struct Size(pub u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+        let mut value = self.0;
+        let mut unit = 0;
+        while value >= 10 * 1024 && unit + 1 < UNITS.len() {
+            value /= 1024;
+            unit += 1;
+        }
+        write!(f, "{} {}", value, UNITS[unit])
+    }
+}
+
+pub fn meminfo(_argv: Vec<&str>) {
+    let boot_info = match memory::boot_info() {
+        Some(boot_info) => boot_info,
+        None => {
+            println!("Boot information is not available.");
+            return;
+        }
+    };
+
+    println!("Physical memory map:");
+    let mut totals: Vec<(MemoryRegionType, u64)> = Vec::new();
+    for region in boot_info.memory_map.iter() {
+        let (start, end) = (region.range.start_addr(), region.range.end_addr());
+        println!("  {:#012x} - {:#012x} {:>9}  {:?}", start, end, Size(end - start), region.region_type);
+
+        match totals.iter_mut().find(|(t, _)| *t == region.region_type) {
+            Some((_, total)) => *total += end - start,
+            None => totals.push((region.region_type, end - start)),
+        }
+    }
+
+    println!("Totals by type:");
+    for (region_type, total) in totals {
+        println!("  {:<18} {:>9}", alloc::format!("{:?}", region_type), Size(total));
+    }
+
+    if let Some(frames) = memory::FRAME_ALLOCATOR.lock().as_ref() {
+        let stats = frames.stats();
+        println!("Frames: {} used, {} free, {} total ({} free)",
+            stats.used_frames(), stats.free_frames, stats.total_frames,
+            Size(stats.free_frames as u64 * 4096));
+    }
+
+    let heap = memory::allocator::stats();
+    println!("Heap: {} mapped of {} limit; {} in use by {} allocations (peak {})",
+        Size(memory::allocator::heap_size() as u64),
+        Size(memory::allocator::heap_limit() as u64),
+        Size(heap.bytes_in_use as u64),
+        heap.live_allocations,
+        Size(heap.peak_bytes_in_use as u64));
+}
+
+/// Flags which say nothing about how a page may be used.
+fn interesting(flags: PageTableFlags) -> PageTableFlags {
+    flags - (PageTableFlags::PRESENT
+        | PageTableFlags::ACCESSED
+        | PageTableFlags::DIRTY
+        | PageTableFlags::HUGE_PAGE)
+}
+
+/// A run of mappings which are contiguous in both address spaces
+/// and share a page size and flags.
+struct Run {
+    start: VirtAddr,
+    frame: PhysAddr,
+    size: MappedPageSize,
+    pages: u64,
+    flags: PageTableFlags,
+}
+
+impl Run {
+    fn end(&self) -> VirtAddr {
+        self.start + self.pages * self.size.bytes()
+    }
+
+    fn extends_to(&self, mapping: &Mapping) -> bool {
+        mapping.size == self.size
+            && mapping.start == self.end()
+            && mapping.frame == self.frame + self.pages * self.size.bytes()
+            && interesting(mapping.flags) == self.flags
+    }
+
+    fn print(&self) {
+        let size = match self.size {
+            MappedPageSize::Size4KiB => "4K",
+            MappedPageSize::Size2MiB => "2M",
+            MappedPageSize::Size1GiB => "1G",
+        };
+        println!("  {:#018x}-{:#018x} -> {:#012x} {:>5} x {} {:?}",
+            self.start.as_u64(), self.end().as_u64(), self.frame.as_u64(),
+            self.pages, size, self.flags);
+    }
+}
+
+pub fn ptdump(_argv: Vec<&str>) {
+    let offset = match memory::physical_memory_offset() {
+        Some(offset) => offset,
+        None => {
+            println!("Paging is not initialized.");
+            return;
+        }
+    };
+
+    println!("Present mappings of the active page tables:");
+    let walker = unsafe { PageTableWalker::active(offset) };
+    let mut run: Option<Run> = None;
+    for mapping in walker.mappings() {
+        match run.as_mut() {
+            Some(run) if run.extends_to(&mapping) => run.pages += 1,
+            _ => {
+                if let Some(run) = run.take() {
+                    run.print();
+                }
+                run = Some(Run {
+                    start: mapping.start,
+                    frame: mapping.frame,
+                    size: mapping.size,
+                    pages: 1,
+                    flags: interesting(mapping.flags),
+                });
+            }
+        }
+    }
+    if let Some(run) = run {
+        run.print();
+    }
+}
+
+pub fn translate(argv: Vec<&str>) {
+    let addr = match argv.get(1).and_then(|arg| parse_addr(arg)) {
+        Some(addr) => addr,
+        None => {
+            println!("Usage: translate <canonical virtual address in hex>");
+            return;
+        }
+    };
+    let offset = match memory::physical_memory_offset() {
+        Some(offset) => offset,
+        None => {
+            println!("Paging is not initialized.");
+            return;
+        }
+    };
+
+    match unsafe { memory::translate_addr(addr, offset) } {
+        Some(translation) => println!("{:#x} -> {:#x} ({:?} page, {:?})",
+            addr.as_u64(), translation.addr.as_u64(), translation.size,
+            interesting(translation.flags)),
+        None => println!("{:#x} is not mapped.", addr.as_u64()),
+    }
+}
+
+fn parse_addr(arg: &str) -> Option<VirtAddr> {
+    let digits = arg.trim_start_matches("0x").replace('_', "");
+    let addr = u64::from_str_radix(&digits, 16).ok()?;
+    VirtAddr::try_new(addr).ok()
+}
@@ -9,6 +9,8 @@ use super::keyboard::{ScancodeStream};
 
 mod util;
 mod fs;
+mod mem;
+mod sys;
 
 /// The Kernel Shell takes full control of the serial keyboard driver.
 /// 
@@ -33,6 +35,12 @@ pub async fn ksh_main() {
             "ls" | "dir" => fs::ls(s),
             "run" | "exec" => fs::run(s),
             "print" | "show" => fs::print(s),
+            "meminfo" | "memmap" => mem::meminfo(s),
+            "ptdump" => mem::ptdump(s),
+            "translate" => mem::translate(s),
+            "acpi" => sys::acpi(s),
+            "shutdown" => sys::shutdown(s),
+            "reboot" => sys::reboot(s),
             "help" => util::help(),
             _ => println!("Unknown command. Type 'help' for a list of commands."),
         }
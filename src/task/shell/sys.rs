@@ -0,0 +1,68 @@
+//! System commands.
+use alloc::vec::Vec;
+use crate::acpi::{self, MadtEntry};
+use crate::{power, println};
+use super::mem::Size;
+
+pub fn acpi(_argv: Vec<&str>) {
+    let rsdp = match acpi::rsdp() {
+        Ok(rsdp) => rsdp,
+        Err(error) => {
+            println!("No ACPI tables: {:?}", error);
+            return;
+        }
+    };
+    println!("RSDP at {:#x}: revision {}, OEM {}", rsdp.addr().as_u64(), rsdp.revision(), rsdp.oem_id());
+
+    let tables = match acpi::tables() {
+        Ok(tables) => tables,
+        Err(error) => {
+            println!("Cannot read the root table: {:?}", error);
+            return;
+        }
+    };
+    for table in tables {
+        println!("  {:<4} {:#010x} {:>9}  rev {}  {:<6} {:<8}{}",
+            table.signature(), table.addr().as_u64(), Size(table.len() as u64), table.revision(),
+            table.oem_id(), table.oem_table_id(),
+            if table.checksum_valid() { "" } else { "  bad checksum" });
+    }
+
+    if let Some(madt) = acpi::madt() {
+        let (mut cpus, mut io_apics) = (0, 0);
+        for entry in madt.entries() {
+            match entry {
+                MadtEntry::LocalApic { .. } | MadtEntry::LocalX2Apic { .. } => cpus += 1,
+                MadtEntry::IoApic { .. } => io_apics += 1,
+                _ => {}
+            }
+        }
+        println!("MADT: {} processors, {} I/O APICs, local APICs at {:#x}{}",
+            cpus, io_apics, madt.local_apic_address().as_u64(),
+            if madt.pcat_compatible() { ", 8259 PICs present" } else { "" });
+    }
+    if let Some(fadt) = acpi::fadt() {
+        println!("FADT: SCI on IRQ {}, PM1a control {:?}, DSDT {}",
+            fadt.sci_interrupt(), fadt.pm1a_control_block().map(|block| block.address),
+            if fadt.dsdt().is_some() { "valid" } else { "missing" });
+    }
+    if let Some(hpet) = acpi::hpet() {
+        println!("HPET: {} comparators at {:#x}", hpet.comparators(), hpet.base_address().address);
+    }
+    if let Some(mcfg) = acpi::mcfg() {
+        for entry in mcfg.entries() {
+            println!("MCFG: segment {} buses {}-{} at {:#x}",
+                entry.segment, entry.start_bus, entry.end_bus, entry.base_address.as_u64());
+        }
+    }
+}
+
+pub fn shutdown(_argv: Vec<&str>) {
+    println!("Shutting down...");
+    power::shutdown();
+}
+
+pub fn reboot(_argv: Vec<&str>) {
+    println!("Rebooting...");
+    power::reboot();
+}
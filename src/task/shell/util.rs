@@ -15,7 +15,16 @@ pub fn help() {
     ls  <path>:             List directories and files in the initramfs.
     exec <path>:            Run executables in the initramfs.
     print [-a] <path>:      Print the hex values of files in the initramfs.
-                            The -a flag prints the files as ASCII."#);
+                            The -a flag prints the files as ASCII.
+    meminfo:                Show the physical memory map, frame
+                            allocator and heap usage.
+    ptdump:                 List the present mappings of the active
+                            page tables, merging contiguous runs.
+    translate <vaddr>:      Translate a virtual address (in hex).
+    acpi:                   List the ACPI tables and summarize the
+                            MADT, FADT, HPET and MCFG.
+    shutdown:               Turn the machine off.
+    reboot:                 Restart the machine."#);
 }
 
 pub fn echo(mut argv: Vec<&str>) {
@@ -0,0 +1,98 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use rust_os::acpi::{self, aml, AddressSpace, MadtEntry, SleepTypes};
+use rust_os::memory;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let _mapper = unsafe { memory::init(phys_mem_offset) };
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn finds_the_rsdp() {
+    let rsdp = acpi::rsdp().expect("no RSDP");
+    assert!(rsdp.addr().as_u64() >= 0xE0000 || rsdp.addr().as_u64() < 0xA0000);
+    assert!(!rsdp.oem_id().is_empty());
+}
+
+#[test_case]
+fn root_lists_valid_tables() {
+    let mut count = 0;
+    for table in acpi::tables().expect("no root table") {
+        assert!(table.checksum_valid(), "{} has a bad checksum", table.signature());
+        count += 1;
+    }
+    assert!(count > 0);
+    assert!(acpi::find("FACP").is_some());
+    assert!(acpi::find("NONE").is_none());
+}
+
+#[test_case]
+fn madt_lists_the_interrupt_controllers() {
+    let madt = acpi::madt().expect("no MADT");
+    assert!(madt.entries().any(|entry| matches!(entry, MadtEntry::LocalApic { .. })));
+    assert!(madt.entries().any(|entry| matches!(entry, MadtEntry::IoApic { .. })));
+    assert_eq!(madt.local_apic_address().as_u64(), 0xFEE0_0000);
+}
+
+#[test_case]
+fn fadt_leads_to_the_dsdt() {
+    let fadt = acpi::fadt().expect("no FADT");
+    let dsdt = fadt.dsdt().expect("no valid DSDT");
+    assert_eq!(dsdt.signature(), "DSDT");
+    let pm1a = fadt.pm1a_control_block().expect("no PM1a control block");
+    assert_eq!(pm1a.space, AddressSpace::SystemIo);
+}
+
+#[test_case]
+fn hpet_has_a_timer_block() {
+    let hpet = acpi::hpet().expect("no HPET table");
+    assert_eq!(hpet.base_address().space, AddressSpace::SystemMemory);
+    assert_eq!(hpet.base_address().address, 0xFED0_0000);
+    assert!(hpet.comparators() >= 3);
+}
+
+#[test_case]
+fn dsdt_declares_soft_off() {
+    // QEMU's PIIX4 enters S5 with sleep type 0.
+    let s5 = acpi::sleep_types(5).expect("no \\_S5 object");
+    assert_eq!(s5.a, 0);
+}
+
+#[test_case]
+fn parses_sleep_type_packages() {
+    // Name (\_S5, Package (0x04) { 0x05, 0x05, Zero, Zero })
+    let bytes = [0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x0A, 0x04, 0x0A, 0x05, 0x0A, 0x05, 0x00, 0x00];
+    assert_eq!(aml::sleep_types(&bytes, 5), Some(SleepTypes { a: 5, b: 5 }));
+    assert_eq!(aml::sleep_types(&bytes, 3), None);
+
+    // Name (_S3, Package (One) { One }), with a two byte package length.
+    let bytes = [0x10, 0x08, b'_', b'S', b'3', b'_', 0x12, 0x43, 0x00, 0x01, 0x01, 0xA4];
+    assert_eq!(aml::sleep_types(&bytes, 3), Some(SleepTypes { a: 1, b: 0 }));
+
+    // A reference to _S5 rather than its declaration.
+    let bytes = [0x70, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x01, 0x0A, 0x07];
+    assert_eq!(aml::sleep_types(&bytes, 5), None);
+
+    // A declaration cut off right after the name.
+    let bytes = [0x08, b'_', b'S', b'5', b'_'];
+    assert_eq!(aml::sleep_types(&bytes, 5), None);
+}
@@ -0,0 +1,143 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use rust_os::memory::{self, allocator, vmm, AddressSpace};
+use rust_os::memory::address_space::USER_SPACE_START;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use alloc::boxed::Box;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::BuddyFrameAllocator;
+
+    rust_os::init();
+    memory::set_boot_info(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::FRAME_ALLOCATOR.lock().as_ref().unwrap().free_frames()
+}
+
+fn user_page(n: u64) -> Page {
+    Page::containing_address(VirtAddr::new(USER_SPACE_START + n * 4096))
+}
+
+#[test_case]
+fn user_mappings_are_private() {
+    let mut a = AddressSpace::new().unwrap();
+    let b = AddressSpace::new().unwrap();
+    a.map_user(user_page(0), PageTableFlags::WRITABLE).unwrap();
+
+    let translation = a.translate(user_page(0).start_address()).unwrap();
+    assert!(translation.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+    assert!(b.translate(user_page(0).start_address()).is_none());
+}
+
+#[test_case]
+fn kernel_half_is_shared() {
+    let space = AddressSpace::new().unwrap();
+    let boxed = Box::new(42u64);
+    let addr = VirtAddr::from_ptr(&*boxed);
+    assert_eq!(
+        space.translate(addr).map(|t| t.addr),
+        memory::AddressSpace::new().unwrap().translate(addr).map(|t| t.addr),
+    );
+    assert!(space.translate(addr).is_some());
+}
+
+#[test_case]
+fn activate_and_access_user_page() {
+    let mut space = AddressSpace::new().unwrap();
+    space.map_user(user_page(1), PageTableFlags::WRITABLE).unwrap();
+
+    unsafe { space.activate() };
+    assert!(space.is_active());
+    let ptr: *mut u64 = user_page(1).start_address().as_mut_ptr();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+    }
+    // The heap lives in the shared kernel half and must keep working.
+    let boxed = Box::new(7u64);
+    assert_eq!(*boxed, 7);
+
+    drop(space);
+    assert_eq!(
+        x86_64::registers::control::Cr3::read().0,
+        memory::kernel_level_4_frame().unwrap(),
+    );
+}
+
+#[test_case]
+fn drop_frees_all_frames() {
+    let before = free_frames();
+    let mut space = AddressSpace::new().unwrap();
+    for n in 0..16 {
+        space.map_user(user_page(n * 512), PageTableFlags::WRITABLE).unwrap();
+    }
+    space.unmap(user_page(0)).unwrap();
+    assert!(space.translate(user_page(0).start_address()).is_none());
+    drop(space);
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn boot_info_is_shared() {
+    let boot_info = memory::boot_info().unwrap();
+    assert!(boot_info as *const _ as u64 >= 0xFFFF_8000_0000_0000);
+
+    let space = AddressSpace::new().unwrap();
+    unsafe { space.activate() };
+    assert!(boot_info.memory_map.iter().count() > 0);
+    drop(space);
+}
+
+#[test_case]
+fn later_kernel_slots_appear_on_access() {
+    let space = AddressSpace::new().unwrap();
+
+    // A level 4 slot of the kernel half which nothing uses yet.
+    let page: Page = Page::containing_address(VirtAddr::new(0xFFFF_C000_0000_0000));
+    assert!(space.translate(page.start_address()).is_none());
+    vmm::vmm().reserve_at(page.start_address(), 4096, "test slot").unwrap();
+    memory::with_kernel_memory(|mapper, frames| {
+        let frame = frames.allocate_frame().unwrap();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { mapper.map_to(page, frame, flags, frames) }.unwrap().flush();
+    });
+
+    unsafe { space.activate() };
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(0x5107);
+        assert_eq!(ptr.read_volatile(), 0x5107);
+    }
+    assert!(space.translate(page.start_address()).is_some());
+    drop(space);
+}
@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use rust_os::interrupts::{self, apic};
+use rust_os::memory::{self, allocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::BuddyFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// Waits for `count` timer interrupts, giving up after a generous number of other wakeups.
+fn wait_for_ticks(count: u64) -> bool {
+    let target = interrupts::timer_ticks() + count;
+    for _ in 0..1000 {
+        if interrupts::timer_ticks() >= target {
+            return true;
+        }
+        x86_64::instructions::hlt();
+    }
+    false
+}
+
+// The cases run in order: the first switches to the APIC for the rest.
+
+#[test_case]
+fn timer_runs_on_the_pic() {
+    assert!(!apic::enabled());
+    assert!(wait_for_ticks(3), "no timer interrupts through the PIC");
+}
+
+#[test_case]
+fn init_switches_to_the_apic() {
+    apic::init().expect("APIC initialization failed");
+    assert!(apic::enabled());
+    assert!(apic::local_apic_id().is_some());
+    assert!(matches!(apic::init(), Err(apic::ApicError::AlreadyInitialized)));
+}
+
+#[test_case]
+fn pics_are_masked() {
+    let masks = unsafe { interrupts::PICS.lock().read_masks() };
+    assert_eq!(masks, [0xFF, 0xFF]);
+}
+
+#[test_case]
+fn timer_runs_on_the_apic() {
+    // Without an end of interrupt to the local APIC only one tick would arrive.
+    assert!(wait_for_ticks(3), "no timer interrupts through the I/O APIC");
+}
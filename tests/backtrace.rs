@@ -0,0 +1,124 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::fmt::{self, Write};
+use rust_os::crash::{symbols, Backtrace, Registers};
+use rust_os::memory;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let _mapper = unsafe { memory::init(phys_mem_offset) };
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// Formats into a fixed buffer, so the tests need no heap.
+struct Buffer {
+    bytes: [u8; 128],
+    len: usize,
+}
+
+impl Buffer {
+    fn format(args: fmt::Arguments) -> Self {
+        let mut buffer = Buffer { bytes: [0; 128], len: 0 };
+        buffer.write_fmt(args).unwrap();
+        buffer
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap()
+    }
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.bytes.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+fn function_name(addr: u64) -> &'static str {
+    symbols::lookup(addr).expect("address not in the symbol table").name
+}
+
+#[inline(never)]
+fn outer() -> [u64; 4] {
+    core::hint::black_box(middle())
+}
+
+#[inline(never)]
+fn middle() -> [u64; 4] {
+    core::hint::black_box(inner())
+}
+
+#[inline(never)]
+fn inner() -> [u64; 4] {
+    let registers = Registers::capture();
+    let mut frames = [0; 4];
+    for (frame, address) in frames.iter_mut().zip(Backtrace::new(registers.rip, registers.rbp)) {
+        *frame = address;
+    }
+    frames
+}
+
+#[test_case]
+fn finds_own_functions() {
+    let addr = outer as *const () as u64;
+    let symbol = symbols::lookup(addr).expect("address not in the symbol table");
+    assert_eq!(symbol.start, addr);
+    assert!(symbol.size > 0);
+    assert!(symbol.name.contains("5outer"), "unexpected symbol {}", symbol.name);
+}
+
+#[test_case]
+fn capture_reads_the_current_registers() {
+    let local = 0u64;
+    let registers = Registers::capture();
+    let here = VirtAddr::from_ptr(&local).as_u64();
+    assert!(registers.rsp <= here && here - registers.rsp < 4096);
+    assert!(registers.rbp > here);
+    assert!(function_name(registers.rip).contains("capture_reads_the_current_registers"));
+}
+
+#[test_case]
+fn backtrace_follows_frame_pointers() {
+    let frames = outer();
+    assert!(function_name(frames[0]).contains("5inner"));
+    assert!(function_name(frames[1] - 1).contains("6middle"));
+    assert!(function_name(frames[2] - 1).contains("5outer"));
+    assert!(function_name(frames[3] - 1).contains("backtrace_follows_frame_pointers"));
+}
+
+#[test_case]
+fn demangles_legacy_names() {
+    let cases = [
+        ("_ZN4core3ptr13drop_in_place17h0123456789abcdefE", "core::ptr::drop_in_place"),
+        (
+            "_ZN70_$LT$alloc..vec..Vec$LT$T$C$A$GT$$u20$as$u20$core..ops..drop..Drop$GT$4drop17hfedcba9876543210E",
+            "<alloc::vec::Vec<T,A> as core::ops::drop::Drop>::drop",
+        ),
+        ("_ZN7rust_os5crash6report28_$u7b$$u7b$closure$u7d$$u7d$17h0000000000000000E", "rust_os::crash::report::{{closure}}"),
+        ("_RNvCs1234_7rust_os4kmain", "_RNvCs1234_7rust_os4kmain"),
+        ("memcpy", "memcpy"),
+    ];
+    for &(mangled, demangled) in cases.iter() {
+        assert_eq!(Buffer::format(format_args!("{}", symbols::Demangled(mangled))).as_str(), demangled);
+    }
+}
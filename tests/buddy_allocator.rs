@@ -0,0 +1,146 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use rust_os::memory::{self, BuddyFrameAllocator, FreeError};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+static FRAMES: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let _mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    *FRAMES.lock() = Some(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn small_frames_are_unique() {
+    let mut guard = FRAMES.lock();
+    let frames = guard.as_mut().unwrap();
+
+    let a: PhysFrame<Size4KiB> = frames.allocate_frame().expect("out of frames");
+    let b: PhysFrame<Size4KiB> = frames.allocate_frame().expect("out of frames");
+    assert_ne!(a, b);
+
+    unsafe {
+        frames.deallocate_frame(a);
+        frames.deallocate_frame(b);
+    }
+}
+
+#[test_case]
+fn huge_frames_are_aligned() {
+    let mut guard = FRAMES.lock();
+    let frames = guard.as_mut().unwrap();
+
+    let before = frames.stats();
+    let frame: PhysFrame<Size2MiB> = frames.allocate_frame().expect("out of 2 MiB frames");
+    assert_eq!(frame.start_address().as_u64() % (2 * 1024 * 1024), 0);
+    assert_eq!(frames.free_frames(), before.free_frames - 512);
+
+    unsafe { frames.deallocate_frame(frame) };
+    assert_eq!(frames.stats(), before);
+}
+
+#[test_case]
+fn freed_frames_coalesce() {
+    let mut guard = FRAMES.lock();
+    let frames = guard.as_mut().unwrap();
+
+    let huge: PhysFrame<Size2MiB> = frames.allocate_frame().expect("out of 2 MiB frames");
+
+    // Hand the block back one small frame at a time; the
+    // pieces must merge back into the original 2 MiB block.
+    for small in PhysFrame::<Size4KiB>::range(
+        PhysFrame::containing_address(huge.start_address()),
+        PhysFrame::containing_address(huge.start_address() + huge.size()),
+    ) {
+        unsafe { frames.deallocate_frame(small) };
+    }
+
+    let again: PhysFrame<Size2MiB> = frames.allocate_frame().expect("out of 2 MiB frames");
+    assert_eq!(huge, again);
+    unsafe { frames.deallocate_frame(again) };
+}
+
+#[test_case]
+fn double_frees_are_refused() {
+    let mut guard = FRAMES.lock();
+    let frames = guard.as_mut().unwrap();
+
+    let huge: PhysFrame<Size2MiB> = frames.allocate_frame().expect("out of 2 MiB frames");
+    unsafe { frames.deallocate_frame(huge) };
+    let before = frames.stats();
+
+    // Neither the head of the freed block nor a frame inside it may be freed again.
+    let head = huge.start_address();
+    assert_eq!(unsafe { frames.try_deallocate_block(head, 9) }, Err(FreeError::AlreadyFree));
+    assert_eq!(unsafe { frames.try_deallocate_block(head, 0) }, Err(FreeError::AlreadyFree));
+    assert_eq!(unsafe { frames.try_deallocate_block(head + 5 * 4096u64, 0) }, Err(FreeError::AlreadyFree));
+    assert_eq!(frames.stats(), before);
+}
+
+#[test_case]
+fn out_of_range_frees_are_refused() {
+    let mut guard = FRAMES.lock();
+    let frames = guard.as_mut().unwrap();
+
+    let before = frames.stats();
+    let beyond = PhysAddr::new(1 << 40);
+    assert_eq!(unsafe { frames.try_deallocate_block(beyond, 0) }, Err(FreeError::OutOfRange));
+    assert_eq!(unsafe { frames.try_deallocate_block(beyond + 4096u64, 0) }, Err(FreeError::OutOfRange));
+    assert_eq!(unsafe { frames.try_deallocate_block(beyond + 4096u64, 1) }, Err(FreeError::Misaligned));
+    assert_eq!(frames.stats(), before);
+}
+
+/// Both frame allocators zero through `memory::scrub`, so testing the
+/// buddy allocator, which the kernel uses, covers the bitmap one too.
+#[test_case]
+fn reused_frames_are_zeroed() {
+    let mut guard = FRAMES.lock();
+    let frames = guard.as_mut().unwrap();
+    let offset = memory::physical_memory_offset().unwrap();
+    let bytes = |frame: PhysFrame| unsafe {
+        core::slice::from_raw_parts_mut((offset + frame.start_address().as_u64()).as_mut_ptr::<u8>(), 4096)
+    };
+
+    let mut dirty = [None; 64];
+    for slot in dirty.iter_mut() {
+        let frame: PhysFrame = frames.allocate_frame().expect("out of frames");
+        bytes(frame).fill(0xAB);
+        *slot = Some(frame);
+    }
+    for frame in dirty.iter().flatten() {
+        unsafe { frames.deallocate_frame(*frame) };
+    }
+
+    let mut fresh = [None; 64];
+    for slot in fresh.iter_mut() {
+        let frame: PhysFrame = frames.allocate_frame().expect("out of frames");
+        assert!(bytes(frame).iter().all(|&b| b == 0), "frame {:?} was not zeroed", frame);
+        *slot = Some(frame);
+    }
+    for frame in fresh.iter().flatten() {
+        unsafe { frames.deallocate_frame(*frame) };
+    }
+}
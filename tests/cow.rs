@@ -0,0 +1,150 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use rust_os::memory::{self, allocator, cow, vmm, AddressSpace};
+use rust_os::memory::address_space::USER_SPACE_START;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::BuddyFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn phys(addr: VirtAddr) -> PhysAddr {
+    let offset = memory::physical_memory_offset().unwrap();
+    unsafe { memory::translate_addr(addr, offset) }.unwrap().addr
+}
+
+fn owners(addr: PhysAddr) -> usize {
+    memory::FRAME_ALLOCATOR.lock().as_ref().unwrap().owners(addr)
+}
+
+fn read(page: Page) -> u64 {
+    unsafe { page.start_address().as_ptr::<u64>().read_volatile() }
+}
+
+fn write(page: Page, value: u64) {
+    unsafe { page.start_address().as_mut_ptr::<u64>().write_volatile(value) }
+}
+
+/// Maps one fresh writable frame at the first of two reserved pages.
+fn two_pages() -> (Page, Page) {
+    let start = vmm::reserve(2 * 4096, "cow test").unwrap();
+    let a = Page::containing_address(start);
+    memory::with_kernel_memory(|mapper, frames| {
+        let frame: PhysFrame = frames.allocate_frame().unwrap();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { mapper.map_to(a, frame, flags, frames) }.unwrap().flush();
+    });
+    (a, a + 1)
+}
+
+#[test_case]
+fn write_copies_shared_frame() {
+    let (a, b) = two_pages();
+    write(a, 1);
+    memory::with_kernel_memory(|mapper, frames| cow::share_page(mapper, frames, a, b)).unwrap();
+
+    let shared = phys(a.start_address());
+    assert_eq!(phys(b.start_address()), shared);
+    assert_eq!(owners(shared), 2);
+    assert_eq!(read(b), 1);
+
+    write(b, 2);
+    assert_eq!(read(a), 1);
+    assert_eq!(read(b), 2);
+    assert_ne!(phys(b.start_address()), shared);
+    assert_eq!(owners(shared), 1);
+
+    // The last owner keeps its frame and just becomes writable again.
+    write(a, 3);
+    assert_eq!(phys(a.start_address()), shared);
+    assert_eq!(read(a), 3);
+}
+
+#[test_case]
+fn fork_shares_user_pages() {
+    let page = Page::containing_address(VirtAddr::new(USER_SPACE_START));
+    let mut parent = AddressSpace::new().unwrap();
+    let frame = parent.map_user(page, PageTableFlags::WRITABLE).unwrap();
+    let child = parent.fork().unwrap();
+    assert_eq!(child.translate(page.start_address()).unwrap().addr, frame.start_address());
+    assert_eq!(owners(frame.start_address()), 2);
+
+    unsafe { child.activate() };
+    write(page, 0x1234);
+    assert_ne!(child.translate(page.start_address()).unwrap().addr, frame.start_address());
+    drop(child);
+
+    let offset = memory::physical_memory_offset().unwrap();
+    let parent_value = unsafe { (offset + frame.start_address().as_u64()).as_ptr::<u64>().read_volatile() };
+    assert_eq!(parent_value, 0);
+    assert_eq!(owners(frame.start_address()), 1);
+}
+
+/// A frame the frame allocator never owned, like those of the initrd.
+#[repr(C, align(4096))]
+struct Borrowed([u64; 512]);
+
+static BORROWED_PAGE: Borrowed = Borrowed([0x5678; 512]);
+
+fn free_frames() -> usize {
+    memory::FRAME_ALLOCATOR.lock().as_ref().unwrap().free_frames()
+}
+
+#[test_case]
+fn fork_copies_borrowed_pages() {
+    let page = Page::containing_address(VirtAddr::new(USER_SPACE_START));
+    let frame = PhysFrame::containing_address(phys(VirtAddr::from_ptr(&BORROWED_PAGE)));
+    let before = free_frames();
+
+    let mut parent = AddressSpace::new().unwrap();
+    unsafe { parent.map_to(page, frame, PageTableFlags::WRITABLE | memory::BORROWED) }.unwrap();
+    let child = parent.fork().unwrap();
+
+    // Both writers get a private copy, which they own.
+    unsafe { child.activate() };
+    write(page, 0x1234);
+    unsafe { parent.activate() };
+    write(page, 0x9abc);
+    let offset = memory::physical_memory_offset().unwrap();
+    for (space, value) in [(&parent, 0x9abc), (&child, 0x1234)] {
+        let copy = space.translate(page.start_address()).unwrap();
+        assert_ne!(copy.addr, frame.start_address());
+        assert!(copy.flags.contains(PageTableFlags::WRITABLE));
+        assert!(!copy.flags.intersects(memory::BORROWED | cow::COW));
+        assert_eq!(unsafe { (offset + copy.addr.as_u64()).as_ptr::<u64>().read_volatile() }, value);
+    }
+    assert_eq!(BORROWED_PAGE.0[0], 0x5678);
+
+    drop(child);
+    drop(parent);
+    assert_eq!(free_frames(), before);
+}
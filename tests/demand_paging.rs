@@ -0,0 +1,122 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use rust_os::memory::{self, allocator, vmm, AddressSpace};
+use rust_os::memory::address_space::USER_SPACE_START;
+use rust_os::memory::demand::Backing;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::BuddyFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    let offset = memory::physical_memory_offset().unwrap();
+    unsafe { memory::translate_addr(addr, offset) }.is_some()
+}
+
+fn free_frames() -> usize {
+    memory::FRAME_ALLOCATOR.lock().as_ref().unwrap().free_frames()
+}
+
+/// Far more than the tests ever touch.
+const HUGE: u64 = 1024 * 1024 * 1024;
+
+#[test_case]
+fn zero_fill_region_is_backed_on_touch() {
+    let before = free_frames();
+    let start = vmm::reserve_lazy(HUGE, PageTableFlags::WRITABLE, Backing::Zero, "lazy test").unwrap();
+    assert_eq!(free_frames(), before);
+
+    let far = start + (HUGE - 4096);
+    assert!(!is_mapped(far));
+    unsafe {
+        assert_eq!(far.as_ptr::<u64>().read_volatile(), 0);
+        far.as_mut_ptr::<u64>().write_volatile(99);
+        assert_eq!(far.as_ptr::<u64>().read_volatile(), 99);
+    }
+    assert!(is_mapped(far));
+    assert!(!is_mapped(start));
+
+    vmm::free(start).unwrap();
+    assert!(!is_mapped(far));
+}
+
+static FILE: [u8; 5000] = {
+    let mut data = [0u8; 5000];
+    let mut i = 0;
+    while i < data.len() {
+        data[i] = (i % 251) as u8 + 1;
+        i += 1;
+    }
+    data
+};
+
+#[test_case]
+fn file_backed_region_reads_data() {
+    let start = vmm::reserve_lazy(3 * 4096, PageTableFlags::empty(), Backing::File(&FILE), "lazy file").unwrap();
+    let bytes = unsafe { core::slice::from_raw_parts(start.as_ptr::<u8>(), 3 * 4096) };
+    assert_eq!(&bytes[..FILE.len()], &FILE[..]);
+    assert!(bytes[FILE.len()..].iter().all(|&b| b == 0));
+    vmm::free(start).unwrap();
+}
+
+#[test_case]
+fn user_region_is_backed_on_touch() {
+    let start = VirtAddr::new(USER_SPACE_START);
+    let mut space = AddressSpace::new().unwrap();
+    space.map_lazy(start, HUGE, PageTableFlags::WRITABLE, Backing::Zero).unwrap();
+    assert!(space.translate(start).is_none());
+
+    unsafe {
+        space.activate();
+        start.as_mut_ptr::<u32>().write_volatile(7);
+        assert_eq!(start.as_ptr::<u32>().read_volatile(), 7);
+    }
+    let translation = space.translate(start).unwrap();
+    assert!(translation.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+}
+
+#[test_case]
+fn kernel_region_touched_in_user_space_is_shared() {
+    let start = vmm::reserve_lazy(4096, PageTableFlags::WRITABLE, Backing::Zero, "lazy kernel test").unwrap();
+    let space = AddressSpace::new().unwrap();
+    unsafe {
+        space.activate();
+        start.as_mut_ptr::<u64>().write_volatile(5);
+    }
+
+    // Dropping the active address space switches back to the kernel's tables.
+    drop(space);
+    assert!(is_mapped(start));
+    assert_eq!(unsafe { start.as_ptr::<u64>().read_volatile() }, 5);
+    vmm::free(start).unwrap();
+}
@@ -0,0 +1,148 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use bootloader::{entry_point, BootInfo};
+use rust_os::{exit_qemu, prints, printsln, PanicMessage, QemuExitCode};
+
+/// One exception to raise. `expected` is part of the panic message the
+/// kernel's handler must produce, or empty if execution should resume.
+struct Case {
+    name: &'static str,
+    expected: &'static str,
+    trigger: fn(),
+}
+
+const CASES: &[Case] = &[
+    Case { name: "divide_by_zero", expected: "EXCEPTION: DIVIDE BY ZERO", trigger: divide_by_zero },
+    Case { name: "debug", expected: "", trigger: single_step },
+    Case { name: "non_maskable_interrupt", expected: "EXCEPTION: NON-MASKABLE INTERRUPT", trigger: || unsafe { asm!("int 2") } },
+    Case { name: "breakpoint", expected: "", trigger: x86_64::instructions::interrupts::int3 },
+    Case { name: "overflow", expected: "EXCEPTION: OVERFLOW", trigger: || unsafe { asm!("int 4") } },
+    Case { name: "bound_range_exceeded", expected: "EXCEPTION: BOUND RANGE EXCEEDED", trigger: || unsafe { asm!("int 5") } },
+    Case { name: "invalid_opcode", expected: "EXCEPTION: INVALID OPCODE", trigger: || unsafe { asm!("ud2") } },
+    Case { name: "device_not_available", expected: "EXCEPTION: DEVICE NOT AVAILABLE", trigger: device_not_available },
+    Case { name: "invalid_tss", expected: "EXCEPTION: INVALID TSS, error code 0x28 (GDT index 0x5)", trigger: || deliver(10, 0x28) },
+    Case { name: "segment_not_present", expected: "EXCEPTION: SEGMENT NOT PRESENT, error code 0x402 (IDT index 0x80)", trigger: || unsafe { asm!("int 0x80") } },
+    Case { name: "stack_segment_fault", expected: "EXCEPTION: STACK-SEGMENT FAULT, error code 0x1d (LDT index 0x3, external)", trigger: || deliver(12, 0x1d) },
+    Case { name: "general_protection_fault", expected: "EXCEPTION: GENERAL PROTECTION FAULT, error code 0xff8 (GDT index 0x1ff)", trigger: load_bad_selector },
+    Case { name: "x87_floating_point", expected: "EXCEPTION: x87 FLOATING POINT", trigger: || unsafe { asm!("int 16") } },
+    Case { name: "alignment_check", expected: "EXCEPTION: ALIGNMENT CHECK, error code 0x0", trigger: || deliver(17, 0) },
+    Case { name: "machine_check", expected: "EXCEPTION: MACHINE CHECK", trigger: || unsafe { asm!("int 18") } },
+    Case { name: "simd_floating_point", expected: "EXCEPTION: SIMD FLOATING POINT", trigger: || unsafe { asm!("int 19") } },
+    Case { name: "virtualization", expected: "EXCEPTION: VIRTUALIZATION", trigger: || unsafe { asm!("int 20") } },
+    Case { name: "security_exception", expected: "EXCEPTION: SECURITY EXCEPTION, error code 0x1", trigger: || deliver(30, 1) },
+];
+
+/// The case being run.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// A fresh stack for each case after a fatal exception.
+const STACK_SIZE: usize = 16 * 4096;
+static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    run_cases()
+}
+
+extern "C" fn run_cases() -> ! {
+    loop {
+        let index = NEXT.load(Ordering::SeqCst);
+        let case = match CASES.get(index) {
+            Some(case) => case,
+            None => {
+                exit_qemu(QemuExitCode::Success);
+                loop {}
+            }
+        };
+
+        prints!("exceptions::{}...\t", case.name);
+        (case.trigger)();
+        if !case.expected.is_empty() {
+            printsln!("[failed]\nexecution continued after the exception");
+            exit_qemu(QemuExitCode::Failed);
+        }
+        printsln!("[ok]");
+        NEXT.store(index + 1, Ordering::SeqCst);
+    }
+}
+
+fn divide_by_zero() {
+    unsafe {
+        asm!("div ecx", inout("eax") 1 => _, inout("edx") 0 => _, in("ecx") 0);
+    }
+}
+
+/// Sets the trap flag, so the next instruction raises a debug exception.
+fn single_step() {
+    unsafe { asm!("pushfq", "or qword ptr [rsp], 0x100", "popfq", "nop") };
+}
+
+/// Runs an x87 instruction with CR0.TS set.
+fn device_not_available() {
+    unsafe { asm!("mov rax, cr0", "or rax, 8", "mov cr0, rax", "fnop", out("rax") _) };
+}
+
+/// Loads DS with a selector past the end of the GDT.
+fn load_bad_selector() {
+    unsafe { asm!("mov ds, {0:x}", in(reg) 0xFF8u16) };
+}
+
+/// Delivers exception `vector` with `error_code` the way the CPU would.
+/// Used for exceptions which cannot be raised from 64-bit ring 0.
+fn deliver(vector: usize, error_code: u64) {
+    use x86_64::instructions::segmentation::{Segment, CS};
+
+    let idt = x86_64::instructions::tables::sidt();
+    let entry = (idt.base.as_u64() + vector as u64 * 16) as *const u16;
+    let handler = unsafe {
+        entry.read() as u64
+            | (entry.add(3).read() as u64) << 16
+            | ((entry.add(4) as *const u32).read() as u64) << 32
+    };
+
+    unsafe {
+        asm!(
+            "cli",
+            "push 0",
+            "push rsp",
+            "pushfq",
+            "push {cs}",
+            "push {rip}",
+            "push {code}",
+            "jmp {handler}",
+            cs = in(reg) CS::get_reg().0 as u64,
+            rip = in(reg) deliver as usize as u64,
+            code = in(reg) error_code,
+            handler = in(reg) handler,
+            options(noreturn),
+        )
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let index = NEXT.load(Ordering::SeqCst);
+    let message = PanicMessage::new(info);
+
+    match CASES.get(index) {
+        Some(case) if !case.expected.is_empty() && message.contains(case.expected) => printsln!("[ok]"),
+        _ => {
+            printsln!("[failed]\nError: {}\n", info);
+            exit_qemu(QemuExitCode::Failed);
+            loop {}
+        }
+    }
+
+    // The faulting context is abandoned: start over on a fresh stack.
+    NEXT.store(index + 1, Ordering::SeqCst);
+    unsafe {
+        let top = (core::ptr::addr_of!(STACK) as usize + STACK_SIZE) & !0xF;
+        asm!("mov rsp, {0}", "call {1}", in(reg) top, in(reg) run_cases as usize, options(noreturn));
+    }
+}
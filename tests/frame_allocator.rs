@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use rust_os::memory::{self, BitmapFrameAllocator};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+use x86_64::VirtAddr;
+
+static FRAMES: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let _mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    *FRAMES.lock() = Some(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn frames_are_unique() {
+    let mut guard = FRAMES.lock();
+    let frames = guard.as_mut().unwrap();
+
+    let a = frames.allocate_frame().expect("out of frames");
+    let b = frames.allocate_frame().expect("out of frames");
+    assert_ne!(a, b);
+
+    unsafe {
+        frames.deallocate_frame(a);
+        frames.deallocate_frame(b);
+    }
+}
+
+#[test_case]
+fn freed_frame_is_reused() {
+    let mut guard = FRAMES.lock();
+    let frames = guard.as_mut().unwrap();
+
+    let a = frames.allocate_frame().expect("out of frames");
+    unsafe { frames.deallocate_frame(a) };
+    let b = frames.allocate_frame().expect("out of frames");
+    assert_eq!(a, b);
+    unsafe { frames.deallocate_frame(b) };
+}
+
+#[test_case]
+fn stats_track_allocations() {
+    let mut guard = FRAMES.lock();
+    let frames = guard.as_mut().unwrap();
+
+    let before = frames.stats();
+    assert!(before.free_frames <= before.total_frames);
+
+    let frame = frames.allocate_frame().expect("out of frames");
+    assert_eq!(frames.free_frames(), before.free_frames - 1);
+    assert_eq!(frames.used_frames(), before.used_frames() + 1);
+
+    unsafe { frames.deallocate_frame(frame) };
+    assert_eq!(frames.stats(), before);
+}
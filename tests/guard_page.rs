@@ -0,0 +1,62 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+use rust_os::{exit_qemu, prints, printsln, PanicMessage, QemuExitCode};
+use rust_os::memory::{self, allocator, BuddyFrameAllocator};
+
+entry_point!(main);
+
+/// Overflows a guarded stack under the kernel's own handlers, which
+/// must name the stack in their report.
+fn main(boot_info: &'static BootInfo) -> ! {
+    prints!("guard_page::overflow_is_attributed...\t");
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    rust_os::segmentation::init_stacks();
+
+    let stack = memory::stack::allocate("test stack", 4).expect("stack allocation failed");
+
+    // The usable part of the stack is mapped.
+    let bottom: *mut u64 = stack.bottom().as_mut_ptr();
+    unsafe { bottom.write_volatile(42) };
+
+    unsafe {
+        asm!("mov rsp, {0}", "call {1}", in(reg) stack.top.as_u64(), in(reg) overflow_stack as usize,
+            options(noreturn));
+    }
+}
+
+extern "C" fn overflow_stack() -> ! {
+    stack_overflow();
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow(); // for each recursion, the return address is pushed
+    volatile::Volatile::new(0).read(); // prevent tail recursion optimizations
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if PanicMessage::new(info).contains("STACK OVERFLOW in test stack") {
+        printsln!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        printsln!("[failed]\nError: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
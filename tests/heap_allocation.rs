@@ -24,7 +24,7 @@ fn main(boot_info: &'static BootInfo) -> ! {
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
     };
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
@@ -65,3 +65,20 @@ fn many_boxes() {
         assert_eq!(*x, i);
     }
 }
+
+#[test_case]
+fn stats_count_live_allocations() {
+    use rust_os::memory::allocator;
+
+    let before = allocator::stats();
+    let value = Box::new([0u8; 100]);
+    let during = allocator::stats();
+    assert_eq!(during.live_allocations, before.live_allocations + 1);
+    assert_eq!(during.bytes_in_use, before.bytes_in_use + 100);
+    assert!(during.peak_bytes_in_use >= during.bytes_in_use);
+
+    drop(value);
+    let after = allocator::stats();
+    assert_eq!(after.live_allocations, before.live_allocations);
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+}
@@ -0,0 +1,58 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use rust_os::memory::allocator::{self, HEAP_SIZE};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use alloc::vec::Vec;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::{self, BuddyFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn allocation_larger_than_initial_heap() {
+    let n = HEAP_SIZE * 4;
+    let mut vec: Vec<u8> = Vec::with_capacity(n);
+    vec.resize(n, 0xAB);
+    assert!(allocator::heap_size() > HEAP_SIZE);
+    assert!(vec.iter().all(|&b| b == 0xAB));
+}
+
+#[test_case]
+fn growth_stops_at_limit() {
+    let old_limit = allocator::heap_limit();
+    allocator::set_heap_limit(allocator::heap_size());
+
+    let mut vec: Vec<u8> = Vec::new();
+    assert!(vec.try_reserve(allocator::heap_size() * 2).is_err());
+
+    allocator::set_heap_limit(old_limit);
+    assert!(vec.try_reserve(allocator::heap_size() * 2).is_ok());
+}
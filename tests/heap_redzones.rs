@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use rust_os::{exit_qemu, prints, printsln, PanicMessage, QemuExitCode};
+use rust_os::memory::{self, allocator, BuddyFrameAllocator};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    prints!("heap_redzones::overflow_is_caught...\t");
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    // Well-behaved allocations pass the checks.
+    let fine: Vec<u64> = (0..100).collect();
+    drop(fine);
+
+    let mut bytes: Vec<u8> = Vec::with_capacity(16);
+    unsafe { bytes.as_mut_ptr().add(16).write(0) };
+    drop(bytes);
+
+    printsln!("[failed]\noverflow was not detected");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let message = PanicMessage::new(info);
+    if message.contains("heap corruption") && message.contains("overflow: byte 0 past the end") {
+        printsln!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        printsln!("[failed]\nError: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
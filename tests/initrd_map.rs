@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use rust_os::initrd::{MapFileError, INITRD};
+use rust_os::memory::{self, allocator, vmm, AddressSpace};
+use rust_os::memory::address_space::USER_SPACE_START;
+use alloc::borrow::ToOwned;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{Page, PageTableFlags};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::BuddyFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::FRAME_ALLOCATOR.lock().as_ref().unwrap().free_frames()
+}
+
+#[test_case]
+fn mapped_file_matches_contents() {
+    let name = "/lorem_ipsum.txt";
+    let contents = INITRD.open(name.to_owned()).unwrap();
+    let pages = (contents.len() as u64 + 4095) / 4096;
+
+    let start = vmm::reserve(pages * 4096, "initrd test").unwrap();
+    let file = memory::with_kernel_memory(|mapper, frames| {
+        INITRD.map(name, Page::containing_address(start), PageTableFlags::empty(), mapper, frames)
+    }).unwrap();
+    let mapped_frames = free_frames();
+    assert_eq!(file.len, contents.len());
+    assert_eq!(file.pages, pages);
+
+    let mapped = unsafe { core::slice::from_raw_parts(start.as_ptr::<u8>(), (pages * 4096) as usize) };
+    assert_eq!(&mapped[..contents.len()], contents);
+    assert!(mapped[contents.len()..].iter().all(|&b| b == 0));
+
+    let offset = memory::physical_memory_offset().unwrap();
+    let translation = unsafe { memory::translate_addr(start, offset) }.unwrap();
+    assert!(!translation.flags.contains(PageTableFlags::WRITABLE));
+
+    // Unmapping frees the copied pages but not the archive's own frames.
+    vmm::free(start).unwrap();
+    assert_eq!(free_frames() - mapped_frames, (file.pages - file.shared_pages) as usize);
+}
+
+#[test_case]
+fn missing_file_is_reported() {
+    let mut space = AddressSpace::new().unwrap();
+    let start = Page::containing_address(VirtAddr::new(USER_SPACE_START));
+    assert!(matches!(space.map_file("/no such file", start), Err(MapFileError::NotFound)));
+}
+
+#[test_case]
+fn file_mapped_into_user_space() {
+    let mut space = AddressSpace::new().unwrap();
+    let start = Page::containing_address(VirtAddr::new(USER_SPACE_START));
+    let file = space.map_file("/test", start).unwrap();
+    assert_eq!(file.pages, 1);
+
+    let translation = space.translate(start.start_address()).unwrap();
+    assert!(translation.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+    assert!(!translation.flags.contains(PageTableFlags::WRITABLE));
+
+    unsafe { space.activate() };
+    let mapped = unsafe { core::slice::from_raw_parts(start.start_address().as_ptr::<u8>(), file.len) };
+    assert_eq!(mapped, INITRD.open("/test".to_owned()).unwrap());
+}
@@ -0,0 +1,93 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use rust_os::memory::{self, allocator, CacheMode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::PageTableFlags;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::BuddyFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// The VGA text buffer, which is device memory at a known address.
+const VGA_BUFFER: u64 = 0xb8000;
+
+fn translate(addr: VirtAddr) -> Option<memory::Translation> {
+    let offset = memory::physical_memory_offset().unwrap();
+    unsafe { memory::translate_addr(addr, offset) }
+}
+
+#[test_case]
+fn uncached_mapping_reaches_the_device() {
+    let vga = unsafe { memory::map_mmio(PhysAddr::new(VGA_BUFFER), 4000, CacheMode::Uncached) }.unwrap();
+    let translation = translate(vga.addr()).unwrap();
+    assert_eq!(translation.addr, PhysAddr::new(VGA_BUFFER));
+    assert!(translation.flags.contains(PageTableFlags::NO_CACHE));
+
+    // The last cell of the screen, read back through the identity mapping.
+    let cell = 3998;
+    vga.write::<u16>(cell, 0x0f21);
+    let identity = unsafe { core::ptr::read_volatile((VGA_BUFFER as usize + cell) as *const u16) };
+    assert_eq!(identity, 0x0f21);
+    assert_eq!(vga.read::<u16>(cell), 0x0f21);
+}
+
+#[test_case]
+fn unaligned_range_keeps_offset() {
+    let phys = PhysAddr::new(VGA_BUFFER + 0x10);
+    let mmio = unsafe { memory::map_mmio(phys, 0x20, CacheMode::WriteThrough) }.unwrap();
+    assert_eq!(mmio.addr().as_u64() % 4096, 0x10);
+    assert_eq!(translate(mmio.addr()).unwrap().addr, phys);
+}
+
+#[test_case]
+fn write_combining_mapping() {
+    let mmio = unsafe { memory::map_mmio(PhysAddr::new(VGA_BUFFER), 4096, CacheMode::WriteCombining) }.unwrap();
+    assert_eq!(translate(mmio.addr()).unwrap().addr, PhysAddr::new(VGA_BUFFER));
+    mmio.write::<u16>(0, mmio.read::<u16>(0));
+}
+
+#[test_case]
+fn drop_unmaps() {
+    let mmio = unsafe { memory::map_mmio(PhysAddr::new(VGA_BUFFER), 4096, CacheMode::Uncached) }.unwrap();
+    let addr = mmio.addr();
+    drop(mmio);
+    assert!(translate(addr).is_none());
+    assert!(memory::vmm::vmm().find(addr).is_none());
+}
+
+#[test_case]
+fn try_map_does_not_block() {
+    let phys = PhysAddr::new(VGA_BUFFER);
+    {
+        let _mapper = memory::MAPPER.lock();
+        assert!(unsafe { memory::try_map_mmio(phys, 4096, CacheMode::Uncached) }.is_none());
+    }
+    let mmio = unsafe { memory::try_map_mmio(phys, 4096, CacheMode::Uncached) }.unwrap().unwrap();
+    assert_eq!(translate(mmio.addr()).unwrap().addr, phys);
+}
@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use bootloader::{entry_point, BootInfo};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use rust_os::{exit_qemu, QemuExitCode, printsln, prints};
+use rust_os::memory::{self, allocator, BuddyFrameAllocator};
+
+/// The address the test jumps to.
+static TARGET: AtomicU64 = AtomicU64::new(0);
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    use x86_64::registers::control::Cr2;
+
+    let expected = PageFaultErrorCode::INSTRUCTION_FETCH | PageFaultErrorCode::PROTECTION_VIOLATION;
+    if error_code.contains(expected) && Cr2::read().as_u64() == TARGET.load(Ordering::Relaxed) {
+        printsln!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        printsln!("[failed]\nunexpected fault {:?} at {:?}", error_code, Cr2::read());
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    prints!("no_execute::executing_heap_faults...\t");
+
+    rust_os::segmentation::init();
+    TEST_IDT.load();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::kernel_image::protect(&mut mapper).expect("failed to protect the kernel image");
+
+    // The bootloader's mappings hold no code either.
+    let stack_variable = 0u64;
+    for &addr in [phys_mem_offset, VirtAddr::from_ptr(&stack_variable), VirtAddr::from_ptr(boot_info)].iter() {
+        let flags = unsafe { memory::translate_addr(addr, phys_mem_offset) }.unwrap().flags;
+        assert!(flags.contains(PageTableFlags::NO_EXECUTE), "{:?} is executable", addr);
+    }
+    let mut frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    // A single `ret` instruction.
+    let code = Box::new([0xC3u8; 16]);
+    TARGET.store(code.as_ptr() as u64, Ordering::Relaxed);
+    let function: extern "C" fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+    function();
+
+    panic!("Execution continued after running code on the heap");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,98 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+use rust_os::{exit_qemu, QemuExitCode, printsln, prints};
+use rust_os::memory::{self, allocator, BuddyFrameAllocator};
+use rust_os::task::{executor::Executor, oom, Task};
+
+/// Heap bytes in use before the hog started.
+static BASELINE: AtomicUsize = AtomicUsize::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    prints!("oom_kill::hog_is_killed...\t");
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    allocator::set_heap_limit(allocator::HEAP_SIZE * 4);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(hog()));
+    executor.spawn(Task::with_budget(hog(), 16 * 1024));
+    executor.spawn(Task::new(survivor()));
+    BASELINE.store(allocator::stats().bytes_in_use, Ordering::Relaxed);
+    executor.run();
+}
+
+/// Allocates until the heap runs out, keeping everything in its future.
+async fn hog() {
+    let mut pages: Vec<Box<[u8; 4096]>> = Vec::new();
+    loop {
+        pages.push(Box::new([0xAB; 4096]));
+        YieldNow(false).await;
+    }
+}
+
+/// Waits for both hogs to be killed, one over its budget and one on
+/// running out of heap, then checks that their memory came back.
+async fn survivor() {
+    while oom::kills() < 2 {
+        YieldNow(false).await;
+    }
+
+    let in_use = allocator::stats().bytes_in_use;
+    let baseline = BASELINE.load(Ordering::Relaxed);
+    if in_use > baseline + 4096 {
+        printsln!("[failed]\n{} bytes in use after the kills, {} before the hogs", in_use, baseline);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    if allocator::reserve_in_use() != 0 {
+        printsln!("[failed]\n{} bytes of the reserve still in use", allocator::reserve_in_use());
+        exit_qemu(QemuExitCode::Failed);
+    }
+    let again = Box::new([0u8; 4096]);
+    drop(again);
+
+    printsln!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Returns to the executor once, staying ready.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use rust_os::memory::{self, BuddyFrameAllocator, MappedPageSize, PageTableWalker};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageTable, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+static FRAMES: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let _mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    *FRAMES.lock() = Some(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn offset() -> VirtAddr {
+    memory::physical_memory_offset().expect("paging not initialized")
+}
+
+#[test_case]
+fn translate_stack_variable() {
+    let value: u64 = 0xdead_beef;
+    let addr = VirtAddr::from_ptr(&value);
+
+    let translation = unsafe { memory::translate_addr(addr, offset()) }
+        .expect("stack is not mapped");
+    assert!(translation.flags.contains(PageTableFlags::WRITABLE));
+
+    // Reading the same physical memory through the offset mapping must see the value.
+    let alias: *const u64 = (offset() + translation.addr.as_u64()).as_ptr();
+    assert_eq!(unsafe { alias.read_volatile() }, value);
+}
+
+#[test_case]
+fn translate_physical_memory_mapping() {
+    // The bootloader maps physical memory with huge pages,
+    // which used to panic the translation.
+    let phys = 0x1234_5678u64 % (16 * 1024 * 1024);
+    let translation = unsafe { memory::translate_addr(offset() + phys, offset()) }
+        .expect("physical memory is not mapped");
+    assert_eq!(translation.addr.as_u64(), phys);
+}
+
+#[test_case]
+fn mappings_include_stack_page() {
+    let value: u64 = 0;
+    let addr = VirtAddr::from_ptr(&value);
+
+    let walker = unsafe { PageTableWalker::active(offset()) };
+    let found = walker.mappings().any(|m| {
+        m.start <= addr && addr < m.start + m.size.bytes()
+    });
+    assert!(found);
+}
+
+#[test_case]
+fn huge_page_pat_bit_is_not_part_of_the_frame() {
+    let mut guard = FRAMES.lock();
+    let frames = guard.as_mut().unwrap();
+    let table = |frame: PhysFrame| unsafe { &mut *(offset() + frame.start_address().as_u64()).as_mut_ptr::<PageTable>() };
+
+    // A hierarchy mapping the 2 MiB page at 0x20_0000 to 0x60_0000 with
+    // the PAT bit, which is bit 12 of a huge page entry, set.
+    let level_4: PhysFrame = frames.allocate_frame().expect("out of frames");
+    let level_3: PhysFrame = frames.allocate_frame().expect("out of frames");
+    let level_2: PhysFrame = frames.allocate_frame().expect("out of frames");
+    let parent = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    table(level_4)[0].set_frame(level_3, parent);
+    table(level_3)[0].set_frame(level_2, parent);
+    table(level_2)[1].set_addr(PhysAddr::new(0x60_0000 | 0x1000), parent | PageTableFlags::HUGE_PAGE);
+
+    let walker = unsafe { PageTableWalker::new(level_4, offset()) };
+    let translation = walker.translate(VirtAddr::new(0x20_0123)).expect("huge page is not mapped");
+    assert_eq!(translation.addr.as_u64(), 0x60_0123);
+    assert_eq!(translation.size, MappedPageSize::Size2MiB);
+
+    let mapping = walker.mappings().next().expect("huge page is not listed");
+    assert_eq!(mapping.start.as_u64(), 0x20_0000);
+    assert_eq!(mapping.frame.as_u64(), 0x60_0000);
+
+    unsafe {
+        frames.deallocate_frame(level_2);
+        frames.deallocate_frame(level_3);
+        frames.deallocate_frame(level_4);
+    }
+}
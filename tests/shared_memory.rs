@@ -0,0 +1,121 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use rust_os::memory::{self, allocator, AddressSpace, SharedMemory};
+use rust_os::memory::address_space::USER_SPACE_START;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{Page, PageTableFlags};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::BuddyFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::FRAME_ALLOCATOR.lock().as_ref().unwrap().free_frames()
+}
+
+fn user_page(n: u64) -> Page {
+    Page::containing_address(VirtAddr::new(USER_SPACE_START + n * 4096))
+}
+
+const RW: PageTableFlags = PageTableFlags::WRITABLE;
+
+#[test_case]
+fn kernel_mappings_see_each_others_writes() {
+    let shm = SharedMemory::new(2).unwrap();
+    let a = shm.map_kernel(RW, "shm test").unwrap();
+    let b = shm.map_kernel(RW, "shm test").unwrap();
+    assert_ne!(a.addr(), b.addr());
+
+    unsafe {
+        a.as_mut_ptr::<u64>().add(600).write_volatile(0xC0FFEE);
+        assert_eq!(b.as_mut_ptr::<u64>().add(600).read_volatile(), 0xC0FFEE);
+    }
+}
+
+#[test_case]
+fn address_spaces_share_frames() {
+    let shm = SharedMemory::new(3).unwrap();
+    let mut a = AddressSpace::new().unwrap();
+    let mut b = AddressSpace::new().unwrap();
+    a.map_shared(&shm, user_page(0), RW).unwrap();
+    b.map_shared(&shm, user_page(8), PageTableFlags::empty()).unwrap();
+
+    for i in 0..3 {
+        let in_a = a.translate(user_page(i).start_address()).unwrap();
+        let in_b = b.translate(user_page(8 + i).start_address()).unwrap();
+        assert_eq!(in_a.addr, shm.frames()[i as usize].start_address());
+        assert_eq!(in_a.addr, in_b.addr);
+        assert!(in_a.flags.contains(PageTableFlags::WRITABLE));
+        assert!(!in_b.flags.contains(PageTableFlags::WRITABLE));
+    }
+}
+
+#[test_case]
+fn forked_children_keep_sharing() {
+    let shm = SharedMemory::new(1).unwrap();
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map_shared(&shm, user_page(0), RW).unwrap();
+    let child = parent.fork().unwrap();
+
+    let in_parent = parent.translate(user_page(0).start_address()).unwrap();
+    let in_child = child.translate(user_page(0).start_address()).unwrap();
+    assert_eq!(in_parent.addr, in_child.addr);
+    assert!(in_parent.flags.contains(PageTableFlags::WRITABLE));
+    assert!(in_child.flags.contains(PageTableFlags::WRITABLE));
+}
+
+#[test_case]
+fn frames_freed_after_last_holder() {
+    // Page tables created on first use stay around, so warm up first.
+    {
+        let shm = SharedMemory::new(4).unwrap();
+        let _kernel = shm.map_kernel(RW, "shm test").unwrap();
+        AddressSpace::new().unwrap().map_shared(&shm, user_page(0), RW).unwrap();
+    }
+    let before = free_frames();
+
+    let shm = SharedMemory::new(4).unwrap();
+    let kernel = shm.map_kernel(RW, "shm test").unwrap();
+    let mut space = AddressSpace::new().unwrap();
+    space.map_shared(&shm, user_page(0), RW).unwrap();
+    let frame = shm.frames()[0];
+
+    // The mappings keep the frames alive without the handle.
+    drop(shm);
+    assert!(free_frames() < before);
+    unsafe { *kernel.as_mut_ptr::<u8>() = 7 };
+    assert_eq!(space.translate(user_page(0).start_address()).unwrap().addr, frame.start_address());
+
+    space.unmap_shared(user_page(0), 4).unwrap();
+    drop(space);
+    drop(kernel);
+    assert_eq!(free_frames(), before);
+}
@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use rust_os::memory::{self, allocator, vmm};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::memory::BuddyFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn physical_memory_mapping_is_reserved() {
+    let offset = memory::physical_memory_offset().unwrap();
+    assert_eq!(vmm::vmm().find(offset).map(|r| r.name), Some("bootloader"));
+}
+
+#[test_case]
+fn heap_region_is_reserved() {
+    let heap = allocator::heap_start().unwrap();
+    let vmm = vmm::vmm();
+    let region = vmm.find(heap).unwrap();
+    assert_eq!(region.name, "kernel heap");
+    assert_eq!(region.size, allocator::HEAP_MAX_SIZE as u64);
+}
+
+#[test_case]
+fn reservations_do_not_overlap() {
+    let a = vmm::reserve(3 * 4096, "test a").unwrap();
+    let b = vmm::reserve(4096, "test b").unwrap();
+    assert!(b >= a + 3 * 4096u64 || b + 4096u64 <= a);
+
+    let mut vmm = vmm::vmm();
+    assert!(matches!(vmm.reserve_at(a + 4096u64, 4096, "test c"), Err(vmm::VmmError::Overlap)));
+    vmm.release(a).unwrap();
+    vmm.release(b).unwrap();
+    assert!(vmm.find(a).is_none());
+}
+
+#[test_case]
+fn huge_alignment_runs_out_of_space() {
+    let mut vmm = vmm::vmm();
+    assert!(matches!(vmm.reserve(4096, 1 << 63, "test"), Err(vmm::VmmError::OutOfVirtualSpace)));
+}
+
+#[test_case]
+fn allocate_maps_and_free_returns_frames() {
+    let free_before = memory::FRAME_ALLOCATOR.lock().as_ref().unwrap().free_frames();
+
+    let flags = PageTableFlags::WRITABLE;
+    let start = vmm::allocate(4 * 4096, flags, "test region").unwrap();
+    let words = unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr::<u64>(), 4 * 512) };
+    for (i, word) in words.iter_mut().enumerate() {
+        *word = i as u64;
+    }
+    assert!(words.iter().enumerate().all(|(i, &w)| w == i as u64));
+
+    vmm::free(start).unwrap();
+    assert!(vmm::vmm().find(start).is_none());
+    assert_eq!(memory::FRAME_ALLOCATOR.lock().as_ref().unwrap().free_frames(), free_before);
+}
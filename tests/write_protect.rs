@@ -0,0 +1,64 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use bootloader::{entry_point, BootInfo};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+use rust_os::{exit_qemu, QemuExitCode, printsln, prints};
+use rust_os::memory;
+
+/// The address the test writes to.
+static TARGET: AtomicU64 = AtomicU64::new(0);
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    use x86_64::registers::control::Cr2;
+
+    let expected = PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::PROTECTION_VIOLATION;
+    if error_code.contains(expected) && Cr2::read().as_u64() == TARGET.load(Ordering::Relaxed) {
+        printsln!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        printsln!("[failed]\nunexpected fault {:?} at {:?}", error_code, Cr2::read());
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    prints!("write_protect::write_to_text_faults...\t");
+
+    rust_os::segmentation::init();
+    TEST_IDT.load();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::kernel_image::protect(&mut mapper).expect("failed to protect the kernel image");
+
+    let text = main as usize as *mut u8;
+    TARGET.store(text as u64, Ordering::Relaxed);
+    unsafe { text.write_volatile(0xCC) };
+
+    panic!("Execution continued after writing to .text");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}